@@ -1,10 +1,17 @@
-use clap::Parser;
-use failure::Error;
+use clap::{Args, Parser};
+use failure::{format_err, Error};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
 
 use enclose::enc;
 use futures::channel::oneshot;
+use rocket::serde::json::Json;
 use rocket::State;
 use tapedeck::engine;
+use tapedeck::error::ApiError;
+use tapedeck::profile::{AudioCodec, Container, EncodeProfile, RateControl, VideoCodec};
+use tapedeck::transcode;
 use tapedeck::*;
 use tokio::runtime::Runtime;
 
@@ -26,8 +33,99 @@ struct Cli {
 
 #[derive(Parser, PartialEq, Debug)]
 enum Sub {
-    Record { url: String },
-    Transcode {},
+    Record {
+        url: String,
+        /// mp4, mkv or webm
+        #[clap(long, default_value = "mp4")]
+        container: Container,
+        /// Seconds the recording gets to finalize on SIGINT/SIGTERM
+        #[clap(long, default_value = "30")]
+        shutdown_timeout: u64,
+        #[clap(flatten)]
+        profile: ProfileArgs,
+    },
+    Transcode {
+        input: String,
+        output: String,
+        /// mp4, mkv or webm, picked from the output extension when unset
+        #[clap(long)]
+        container: Option<Container>,
+        /// Scale factor for the output resolution
+        #[clap(long)]
+        scale: Option<f64>,
+        /// Start time in seconds
+        #[clap(long)]
+        start: Option<f64>,
+        /// End time in seconds
+        #[clap(long)]
+        end: Option<f64>,
+        #[clap(flatten)]
+        profile: ProfileArgs,
+    },
+}
+
+/// Encoder settings, anything left unset keeps the `EncodeProfile` default
+#[derive(Args, PartialEq, Debug)]
+struct ProfileArgs {
+    /// x264, x265, vp8, vp9 or av1
+    #[clap(long)]
+    codec: Option<VideoCodec>,
+    #[clap(long)]
+    preset: Option<String>,
+    /// cbr, vbr or crf
+    #[clap(long)]
+    rate_control: Option<RateControl>,
+    /// Video bitrate in kbit/s
+    #[clap(long)]
+    bitrate: Option<u32>,
+    /// Quality level for crf
+    #[clap(long)]
+    quality: Option<u32>,
+    /// Maximum frames between keyframes
+    #[clap(long)]
+    keyframe_interval: Option<u32>,
+    #[clap(long)]
+    framerate: Option<u32>,
+    /// opus or aac
+    #[clap(long)]
+    audio_codec: Option<AudioCodec>,
+    /// Audio bitrate in bit/s
+    #[clap(long)]
+    audio_bitrate: Option<u32>,
+}
+
+impl ProfileArgs {
+    fn to_profile(&self) -> EncodeProfile {
+        let mut profile = EncodeProfile::default();
+        if let Some(codec) = self.codec {
+            profile.codec = codec;
+        }
+        if let Some(preset) = &self.preset {
+            profile.preset = preset.clone();
+        }
+        if let Some(rate_control) = self.rate_control {
+            profile.rate_control = rate_control;
+        }
+        if let Some(bitrate) = self.bitrate {
+            profile.bitrate = bitrate;
+        }
+        if let Some(quality) = self.quality {
+            profile.quality = quality;
+        }
+        if self.keyframe_interval.is_some() {
+            profile.keyframe_interval = self.keyframe_interval;
+        }
+        if let Some(framerate) = self.framerate {
+            profile.framerate = framerate;
+        }
+        if let Some(audio_codec) = self.audio_codec {
+            profile.audio_codec = audio_codec;
+        }
+        if let Some(audio_bitrate) = self.audio_bitrate {
+            profile.audio_bitrate = audio_bitrate;
+        }
+        profile
+    }
 }
 
 enum TapedeckEvent {
@@ -38,15 +136,34 @@ enum TapedeckEvent {
 async fn stop(
     mgr: &State<glib::Sender<ManagerEvent>>,
     app_tx: &State<glib::Sender<TapedeckEvent>>,
-) -> String {
+) -> Result<Json<engine::StopReport>, ApiError> {
     let (tx, rx) = oneshot::channel();
-    mgr.send(ManagerEvent::EngineStop(tx, 0)).unwrap();
-    if let Err(err) = rx.await.unwrap() {
-        return err;
-    }
+    mgr.send(ManagerEvent::EngineStop(tx, 0))
+        .map_err(|_| ApiError::internal("manager is not running".to_owned()))?;
+    let report = rx.await??;
 
     app_tx.send(TapedeckEvent::Shutdown);
-    "stopped".to_owned()
+    Ok(Json(report))
+}
+
+#[post("/pause")]
+async fn pause(mgr: &State<glib::Sender<ManagerEvent>>) -> Result<&'static str, ApiError> {
+    let (tx, rx) = oneshot::channel();
+    mgr.send(ManagerEvent::EnginePause(tx, 0))
+        .map_err(|_| ApiError::internal("manager is not running".to_owned()))?;
+    rx.await??;
+
+    Ok("paused")
+}
+
+#[post("/resume")]
+async fn resume(mgr: &State<glib::Sender<ManagerEvent>>) -> Result<&'static str, ApiError> {
+    let (tx, rx) = oneshot::channel();
+    mgr.send(ManagerEvent::EngineResume(tx, 0))
+        .map_err(|_| ApiError::internal("manager is not running".to_owned()))?;
+    rx.await??;
+
+    Ok("resumed")
 }
 
 fn web_init(
@@ -61,7 +178,7 @@ fn web_init(
                 .manage(ctx)
                 .manage(mgr_sender)
                 .manage(app_sender)
-                .mount("/", routes![stop])
+                .mount("/", routes![stop, pause, resume])
                 .launch()
                 .await
                 .expect("error in web server");
@@ -69,7 +186,12 @@ fn web_init(
     });
 }
 
-pub fn run_record(url: String) -> Result<(), Error> {
+pub fn run_record(
+    url: String,
+    container: Container,
+    profile: EncodeProfile,
+    shutdown_timeout: Duration,
+) -> Result<(), Error> {
     let ctx = glib::MainContext::default();
     ctx.push_thread_default();
     let main_loop = glib::MainLoop::new(Some(&ctx), false);
@@ -83,19 +205,48 @@ pub fn run_record(url: String) -> Result<(), Error> {
         .size((1920, 1080))
         .url(url)
         .gst_debug(false)
+        .encode_enabled(true)
         .encode_dir(Some("/tmp".to_string()))
+        .profile(profile)
+        .container(container)
         .build()
         .unwrap();
 
+    if let Err(errors) = cfg.validate() {
+        let errors: Vec<_> = errors
+            .iter()
+            .map(|e| format!("{}: {}", e.field, e.message))
+            .collect();
+        return Err(format_err!(
+            "invalid recording config: {}",
+            errors.join(", ")
+        ));
+    }
+
     let manager = Manager::new();
 
     let (tx, rx) = oneshot::channel();
     manager.send(ManagerEvent::EngineSpawn(tx, cfg)).unwrap();
 
+    // Quit with the engine's error if it fails to start rather than sit idle
+    let start_err = Rc::new(RefCell::new(None));
+    let (quit_loop, spawn_err) = (main_loop.clone(), start_err.clone());
+    ctx.spawn_local(async move {
+        let r = match rx.await {
+            Ok(r) => r.map_err(|e| format_err!("failed to start recording: {}", e)),
+            Err(_) => Err(format_err!("manager stopped before the recording started")),
+        };
+        if let Err(e) = r {
+            spawn_err.replace(Some(e));
+            quit_loop.quit();
+        }
+    });
+
     let (app_tx, app_rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
-    web_init(ctx.clone(), manager, app_tx);
+    web_init(ctx.clone(), manager.clone(), app_tx);
 
-    ctrlc::set_handler(enc!( (main_loop) move || {
+    ctrlc::set_handler(enc!( (main_loop, manager) move || {
+        shutdown_all(&manager, shutdown_timeout);
         main_loop.quit();
     }))?;
 
@@ -110,18 +261,62 @@ pub fn run_record(url: String) -> Result<(), Error> {
     );
 
     main_loop.run();
-    Ok(())
+    match start_err.take() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+pub fn run_transcode(cfg: transcode::TranscodeConfig) -> Result<(), Error> {
+    pretty_env_logger::init();
+    gst::init()?;
+
+    transcode::transcode(&cfg)
 }
 
 pub fn main() -> Result<(), Error> {
     let args = Cli::parse();
 
     match args.cmd {
-        Sub::Record { url } => {
-            run_record(url);
+        Sub::Record {
+            url,
+            container,
+            shutdown_timeout,
+            profile,
+        } => {
+            let mut encode_profile = profile.to_profile();
+            // The default x264 can't go in webm
+            if container == Container::Webm && profile.codec.is_none() {
+                encode_profile.codec = VideoCodec::Vp9;
+            }
+            run_record(
+                url,
+                container,
+                encode_profile,
+                Duration::from_secs(shutdown_timeout),
+            )?;
         }
-        Sub::Transcode {} => {
-            println!("Not Implemented");
+        Sub::Transcode {
+            input,
+            output,
+            container,
+            scale,
+            start,
+            end,
+            profile,
+        } => {
+            let cfg = transcode::TranscodeConfigBuilder::default()
+                .input(input)
+                .output(output)
+                .profile(profile.to_profile())
+                .container(container)
+                .scale(scale)
+                .start(start)
+                .end(end)
+                .build()
+                .unwrap();
+
+            run_transcode(cfg)?;
         }
     };
 
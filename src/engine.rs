@@ -2,109 +2,1212 @@ use failure::{format_err, Error};
 use futures::channel::{mpsc, oneshot};
 use futures::prelude::*;
 use gst::prelude::*;
+use headless_chrome::protocol::Event as ChromeEvent;
 use headless_chrome::{Browser, LaunchOptions, Tab};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
-use std::io::{BufRead, BufReader};
+use std::fs::OpenOptions;
+use std::hash::Hasher;
+use std::io::{BufRead, BufReader, Write};
 use std::result::Result;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use subprocess::{Exec, Popen, Redirection};
 use x11rb::connection::Connection;
 use x11rb::protocol::xproto::{Atom, AtomEnum, ConnectionExt, GetWindowAttributesReply};
 
-pub enum EngineEvent {}
+use crate::preview::launch_gstreamer_preview;
+use crate::profile::{self, Container, EncodeProfile, VideoCodec};
+use crate::resources::Resources;
+
+const RTMP_BIN_NAME: &str = "rtmp";
+const VIDEO_GATE_NAME: &str = "video_gate";
+const AUDIO_GATE_NAME: &str = "audio_gate";
+
+/// Only every this many bytes of a frame are compared by the idle watchdog, which
+/// is plenty to tell a static page from a changing one
+const FRAME_SAMPLE_STRIDE: usize = 61;
+
+/// How long a subprocess gets to exit on SIGTERM before it's killed
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Things that happen to an engine, sent to the Manager tagged with the engine id.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EngineEvent {
+    Starting,
+    Recording {
+        output: Option<String>,
+    },
+    Paused,
+    Resumed,
+    Eos,
+    /// The encoder was torn down, `finalized` tells whether it drained in time
+    Finished {
+        output: Option<String>,
+        duration_secs: f64,
+        bytes: u64,
+        finalized: bool,
+    },
+    /// An error posted on the encoder's bus. Only fatal errors take the recording
+    /// down, a failing rtmp stream is just detached.
+    Error {
+        source: Option<String>,
+        message: String,
+        fatal: bool,
+    },
+    SubprocessExited {
+        process: Subprocess,
+        status: String,
+    },
+    SubprocessRestarted {
+        process: Subprocess,
+    },
+    PageNavigated {
+        url: String,
+    },
+    /// A segment of a segmented recording was finished
+    SegmentClosed {
+        location: String,
+    },
+    /// The encoder went `idle_timeout` without sound or a change on screen
+    Idle {
+        idle_secs: u64,
+    },
+    /// Always the last event of an engine
+    Stopped,
+}
+
+pub type EventSender = mpsc::UnboundedSender<(u32, EngineEvent)>;
+
+/// The helper processes an engine runs on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Subprocess {
+    Dbus,
+    Xvfb,
+    Pulse,
+    Chromium,
+}
+
+/// What the supervisor does when one of an engine's subprocesses dies.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SupervisePolicy {
+    /// Relaunch it, and Chromium along with it, up to `max_restarts` times
+    Restart,
+    /// Stop the whole engine
+    Fail,
+    /// Report it and carry on
+    Ignore,
+}
+
+impl Default for SupervisePolicy {
+    fn default() -> Self {
+        SupervisePolicy::Restart
+    }
+}
+
+/// What the Manager does when an engine's idle watchdog trips.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleAction {
+    /// Stop the engine, finalizing its recording
+    Stop,
+    /// Only emit an `Idle` event and keep recording
+    Event,
+}
+
+impl Default for IdleAction {
+    fn default() -> Self {
+        IdleAction::Stop
+    }
+}
 
-#[derive(Builder, Debug, PartialEq)]
+#[derive(Builder, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct EngineConfig {
     #[builder(default = "1")]
+    #[serde(default)]
     pub id: u32,
 
     #[builder(default = "(1920,1080)")]
+    #[serde(default = "default_size")]
     pub size: (u32, u32),
 
     #[builder(default = "\"https://tandem.chat\".to_string()")]
     pub url: String,
 
     #[builder(default = "false")]
+    #[serde(default)]
     pub encode_enabled: bool,
 
     #[builder(default = "None")]
+    #[serde(default)]
     pub encode_dir: Option<String>,
 
     #[builder(default = "None")]
+    #[serde(default)]
     pub encode_rtmp: Option<String>,
 
+    /// Split the recording into segments of at most this many seconds
+    #[builder(default = "None")]
+    #[serde(default)]
+    pub segment_max_duration: Option<u64>,
+
+    /// Split the recording into segments of at most this many bytes
+    #[builder(default = "None")]
+    #[serde(default)]
+    pub segment_max_size: Option<u64>,
+
+    /// Write fragmented mp4 with a fragment every this many milliseconds, so the
+    /// file stays playable even if the encoder is never shut down cleanly. Only
+    /// applies to the mp4 container
+    #[builder(default = "None")]
+    #[serde(default)]
+    pub fragment_duration: Option<u32>,
+
+    #[builder(default)]
+    #[serde(default)]
+    pub profile: EncodeProfile,
+
+    /// Container the recording is muxed into, which also picks the file extension
+    #[builder(default)]
+    #[serde(default)]
+    pub container: Container,
+
+    /// Write a live HLS preview under `{hls_dir}/{id}`
+    #[builder(default = "None")]
+    #[serde(default)]
+    pub hls_dir: Option<String>,
+
+    /// Number of segments kept in the HLS playlist
+    #[builder(default = "5")]
+    #[serde(default = "default_hls_playlist_length")]
+    pub hls_playlist_length: u32,
+
+    /// Target duration of each HLS segment in seconds
+    #[builder(default = "2")]
+    #[serde(default = "default_hls_segment_duration")]
+    pub hls_segment_duration: u32,
+
     #[builder(default = "false")]
+    #[serde(default)]
     pub gst_debug: bool,
 
+    /// POSTed to when a recording starts, fails or finishes
+    #[builder(default = "None")]
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+
+    /// Key the webhook payloads are signed with, HMAC-SHA256
+    #[builder(default = "None")]
+    #[serde(default, skip_serializing)]
+    pub webhook_secret: Option<String>,
+
+    /// Seconds the encoder gets to drain on stop before it's forced down, which
+    /// may leave the output unfinalized
+    #[builder(default = "10")]
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+
+    /// What to do when dbus, Xvfb, pulseaudio or Chromium dies
+    #[builder(default)]
+    #[serde(default)]
+    pub supervise_policy: SupervisePolicy,
+
+    /// Restarts allowed under `SupervisePolicy::Restart` before the engine is failed
+    #[builder(default = "3")]
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: u32,
+
+    /// Seconds the encoder may go with neither sound nor a change on screen before
+    /// the idle watchdog trips, the watchdog is off if unset
+    #[builder(default = "None")]
+    #[serde(default)]
+    pub idle_timeout: Option<u64>,
+
+    #[builder(default)]
+    #[serde(default)]
+    pub idle_action: IdleAction,
+
+    /// Audio quieter than this many dB on every channel counts as silence
+    #[builder(default = "-50.0")]
+    #[serde(default = "default_silence_threshold")]
+    pub silence_threshold: f64,
+
+    /// Unix time in seconds to start the engine at, it starts right away if unset
+    /// or already past
+    #[builder(default = "None")]
+    #[serde(default)]
+    pub start_at: Option<u64>,
+
+    /// Seconds the engine runs for before the Manager stops it
+    #[builder(default = "None")]
+    #[serde(default)]
+    pub max_duration: Option<u64>,
+
+    /// The Manager stops the engine once the page navigates to a url starting with
+    /// this, like a webinar's end screen
+    #[builder(default = "None")]
+    #[serde(default)]
+    pub complete_url: Option<String>,
+
+    /// Display and ports to run on, assigned by the Manager's `ResourceAllocator`
+    #[builder(default = "None")]
+    #[serde(skip_deserializing)]
+    pub resources: Option<Resources>,
+
+    #[serde(skip, default = "glib::MainContext::default")]
     pub glib_ctx: glib::MainContext,
 }
 
+fn default_size() -> (u32, u32) {
+    (1920, 1080)
+}
+
+fn default_hls_playlist_length() -> u32 {
+    5
+}
+
+fn default_hls_segment_duration() -> u32 {
+    2
+}
+
+fn default_drain_timeout() -> u64 {
+    10
+}
+
+fn default_max_restarts() -> u32 {
+    3
+}
+
+fn default_silence_threshold() -> f64 {
+    -50.0
+}
+
+/// A problem with one field of an `EngineConfig`.
+#[derive(Debug, Serialize)]
+pub struct ConfigError {
+    pub field: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(field: &str, message: String) -> Self {
+        ConfigError {
+            field: field.to_owned(),
+            message,
+        }
+    }
+}
+
+impl EngineConfig {
+    /// Checks the config for mistakes that would otherwise only show up once the
+    /// engine's subprocesses are already running.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = vec![];
+
+        if let Err(e) = url::Url::parse(&self.url) {
+            errors.push(ConfigError::new("url", format!("invalid url: {}", e)));
+        }
+
+        let (width, height) = self.size;
+        if width == 0 || height == 0 || width % 2 != 0 || height % 2 != 0 {
+            errors.push(ConfigError::new(
+                "size",
+                format!("{}x{} must be non-zero and even", width, height),
+            ));
+        }
+
+        if self.encode_enabled
+            && self.encode_dir.is_none()
+            && self.encode_rtmp.is_none()
+            && self.hls_dir.is_none()
+        {
+            errors.push(ConfigError::new(
+                "encode_enabled",
+                "encoding needs encode_dir, encode_rtmp or hls_dir".to_owned(),
+            ));
+        }
+
+        if self.encode_dir.is_some() {
+            if let Err(e) = self.container.check(&self.profile) {
+                errors.push(ConfigError::new("container", e.to_string()));
+            }
+        }
+
+        if let Some(rtmp) = &self.encode_rtmp {
+            if !rtmp.starts_with("rtmp://") && !rtmp.starts_with("rtmps://") {
+                errors.push(ConfigError::new(
+                    "encode_rtmp",
+                    format!("{} is not an rtmp url", rtmp),
+                ));
+            }
+            if self.profile.codec != VideoCodec::X264 {
+                errors.push(ConfigError::new(
+                    "profile.codec",
+                    format!("rtmp output needs x264, not {:?}", self.profile.codec),
+                ));
+            }
+        }
+
+        if self.hls_dir.is_some() {
+            if !matches!(self.profile.codec, VideoCodec::X264 | VideoCodec::X265) {
+                errors.push(ConfigError::new(
+                    "profile.codec",
+                    format!(
+                        "hls output needs x264 or x265, not {:?}",
+                        self.profile.codec
+                    ),
+                ));
+            }
+            if self.hls_playlist_length == 0 {
+                errors.push(ConfigError::new(
+                    "hls_playlist_length",
+                    "must be at least 1".to_owned(),
+                ));
+            }
+            if self.hls_segment_duration == 0 {
+                errors.push(ConfigError::new(
+                    "hls_segment_duration",
+                    "must be at least 1".to_owned(),
+                ));
+            }
+        }
+
+        if let Some(webhook) = &self.webhook_url {
+            match url::Url::parse(webhook) {
+                Ok(url) if url.scheme() == "http" || url.scheme() == "https" => (),
+                _ => errors.push(ConfigError::new(
+                    "webhook_url",
+                    format!("{} is not an http url", webhook),
+                )),
+            }
+        }
+
+        if self.segment_max_duration == Some(0) {
+            errors.push(ConfigError::new(
+                "segment_max_duration",
+                "must be at least 1".to_owned(),
+            ));
+        }
+        if self.segment_max_size == Some(0) {
+            errors.push(ConfigError::new(
+                "segment_max_size",
+                "must be at least 1".to_owned(),
+            ));
+        }
+        if self.profile.framerate == 0 {
+            errors.push(ConfigError::new(
+                "profile.framerate",
+                "must be at least 1".to_owned(),
+            ));
+        }
+        if self.idle_timeout == Some(0) {
+            errors.push(ConfigError::new(
+                "idle_timeout",
+                "must be at least 1".to_owned(),
+            ));
+        }
+        if self.silence_threshold > 0.0 || self.silence_threshold.is_nan() {
+            errors.push(ConfigError::new(
+                "silence_threshold",
+                format!("{} must be at most 0 dB", self.silence_threshold),
+            ));
+        }
+        if self.max_duration == Some(0) {
+            errors.push(ConfigError::new(
+                "max_duration",
+                "must be at least 1".to_owned(),
+            ));
+        }
+        if let Some(complete) = &self.complete_url {
+            if let Err(e) = url::Url::parse(complete) {
+                errors.push(ConfigError::new(
+                    "complete_url",
+                    format!("invalid url: {}", e),
+                ));
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+
+    /// Whether `start_at` has come, so the engine should start now rather than be
+    /// scheduled.
+    pub fn is_due(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs());
+        self.start_at.map_or(true, |at| at <= now)
+    }
+
+    /// Whether the page navigating to `url` means the recording is over.
+    pub fn is_complete_url(&self, url: &str) -> bool {
+        self.complete_url
+            .as_deref()
+            .map_or(false, |complete| url.starts_with(complete))
+    }
+}
+
+/// Where an engine is in its lifecycle, as tracked by the Manager.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EngineState {
+    /// Launching its subprocesses and loading the page
+    Starting,
+    Running,
+    /// Relaunching a subprocess that died
+    Restarting,
+    /// Draining its encoder and tearing down
+    Stopping,
+    /// Shut down, or failed to start
+    Stopped,
+}
+
+/// Snapshot of an engine, as reported by the Manager.
+#[derive(Debug, Serialize)]
+pub struct EngineStatus {
+    pub id: u32,
+    pub state: EngineState,
+    pub config: EngineConfig,
+    pub uptime_secs: u64,
+    /// State of the encoder pipeline, `None` when not encoding
+    pub pipeline_state: Option<String>,
+    pub paused: bool,
+    pub output: Option<String>,
+    pub bytes_written: u64,
+    pub pids: EnginePids,
+}
+
+impl EngineStatus {
+    /// Status of an engine the Manager knows about but that isn't running, so
+    /// there's nothing to report beyond its config.
+    pub fn idle(config: &EngineConfig, state: EngineState) -> Self {
+        EngineStatus {
+            id: config.id,
+            state,
+            config: config.clone(),
+            uptime_secs: 0,
+            pipeline_state: None,
+            paused: false,
+            output: None,
+            bytes_written: 0,
+            pids: EnginePids::default(),
+        }
+    }
+}
+
+/// Outcome of stopping an engine's encoder.
+#[derive(Debug, Serialize)]
+pub struct StopReport {
+    /// Whether the encoder drained before the timeout, so its output is complete
+    pub finalized: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct EnginePids {
+    pub dbus: Option<u32>,
+    pub xvfb: Option<u32>,
+    pub pulse: Option<u32>,
+    pub chromium: Option<u32>,
+}
+
 pub struct Engine {
     id: u32,
     ctx: glib::MainContext,
+    config: EngineConfig,
+    started: Instant,
+    /// Unix time in milliseconds the engine was created at, keeps the files of
+    /// engines that reuse an id apart
+    run: u128,
+    resources: Resources,
+    display: String,
+    pulse_server: String,
+    encode_dir: Option<String>,
+    encode_rtmp: Option<String>,
+    profile: EncodeProfile,
+    container: Container,
+    segment_max_duration: Option<u64>,
+    segment_max_size: Option<u64>,
+    fragment_duration: Option<u32>,
+    hls: Option<HlsOutput>,
+    encode_count: u32,
+    drain_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    silence_threshold: f64,
+    stopped: bool,
+    events: EventSender,
+    supervise_policy: SupervisePolicy,
+    max_restarts: u32,
+    restarts: u32,
+    ignored: HashSet<Subprocess>,
+    dbus_session: String,
     dbus: Popen,
     xvfb: Popen,
     pulse: Popen,
     browser: Option<Browser>,
-    gst_encode: gst::Pipeline,
-    gst_encode_eos_rx: mpsc::Receiver<bool>,
+    gst_encode: Option<Encoder>,
     gst_debug: Option<gst::Pipeline>,
+    gst_previews: HashMap<u32, gst::Pipeline>,
+    preview_count: u32,
 }
 
-impl Drop for Engine {
+struct FileOutput {
+    location: String,
+    manifest: Option<String>,
+    segment_max_duration: Option<u64>,
+    segment_max_size: Option<u64>,
+    container: Container,
+    fragment_duration: Option<u32>,
+}
+
+impl FileOutput {
+    fn is_segmented(&self) -> bool {
+        self.manifest.is_some()
+    }
+}
+
+#[derive(Clone)]
+struct HlsOutput {
+    dir: String,
+    playlist_length: u32,
+    segment_duration: u32,
+}
+
+/// Where an encoder writes to, at least one must be set.
+struct EncodeOutputs {
+    file: Option<FileOutput>,
+    rtmp: Option<String>,
+    hls: Option<HlsOutput>,
+}
+
+struct Encoder {
+    pipeline: gst::Pipeline,
+    /// Resolves on EOS. Dropping it with the encoder ends the bus handler
+    eos_rx: oneshot::Receiver<()>,
+    output: Option<String>,
+    started: Instant,
+    paused: Arc<AtomicBool>,
+    paused_at: Option<u64>,
+    pause_offset: u64,
+    activity: Option<Activity>,
+    /// Whether the watchdog has tripped since the last activity
+    idle: bool,
+}
+
+/// When the encoder last heard sound above `silence_threshold` or saw the screen
+/// change, for the idle watchdog.
+#[derive(Clone)]
+struct Activity {
+    last: Arc<Mutex<Instant>>,
+    silence_threshold: f64,
+}
+
+impl Activity {
+    fn new(silence_threshold: f64) -> Self {
+        Activity {
+            last: Arc::new(Mutex::new(Instant::now())),
+            silence_threshold,
+        }
+    }
+
+    fn touch(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last.lock().unwrap().elapsed()
+    }
+
+    /// Counts a `level` message as activity if any channel is above the threshold.
+    fn hear(&self, level: &gst::StructureRef) {
+        let loud = level
+            .get::<gst::glib::ValueArray>("rms")
+            .map_or(false, |rms| {
+                rms.iter()
+                    .filter_map(|db| db.get::<f64>().ok())
+                    .any(|db| db > self.silence_threshold)
+            });
+        if loud {
+            self.touch();
+        }
+    }
+}
+
+/// Subprocesses launched so far by `Engine::new`. If startup bails out before
+/// `finish`, dropping the guard tears them down in reverse launch order so they
+/// don't linger holding the engine's display and ports.
+struct StartupGuard {
+    id: u32,
+    stages: Vec<(&'static str, Popen)>,
+}
+
+impl StartupGuard {
+    fn new(id: u32) -> Self {
+        StartupGuard { id, stages: vec![] }
+    }
+
+    fn push(&mut self, name: &'static str, process: Popen) {
+        self.stages.push((name, process));
+    }
+
+    /// Hands the launched processes over to the caller, in launch order.
+    fn finish(mut self) -> Vec<Popen> {
+        self.stages.drain(..).map(|(_, process)| process).collect()
+    }
+}
+
+impl Drop for StartupGuard {
     fn drop(&mut self) {
-        let _ = self.stop();
+        while let Some((name, mut process)) = self.stages.pop() {
+            warn!("[Engine({})] startup failed, stopping {}", self.id, name);
+            shut_down(&mut process);
+        }
     }
 }
 
-impl Engine {
-    pub fn new(cfg: EngineConfig) -> Result<Engine, Error> {
-        let display: &str = &format!(":1{:0>4}", cfg.id);
-        let pulse_server: &str = &format!("tcp:localhost:1{:0>4}", cfg.id);
+/// Whether `pid` is running. A zombie that hasn't been reaped yet has exited.
+fn process_alive(pid: u32) -> bool {
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        // The state follows the command name, which may itself contain ") "
+        Ok(stat) => stat
+            .rsplit(") ")
+            .next()
+            .map_or(false, |rest| !rest.starts_with('Z')),
+        Err(_) => false,
+    }
+}
+
+/// Asks a subprocess to exit, killing it if it hasn't within `SHUTDOWN_GRACE`.
+fn shut_down(process: &mut Popen) {
+    let _ = process.terminate();
+    match process.wait_timeout(SHUTDOWN_GRACE) {
+        Ok(Some(_)) => (),
+        _ => {
+            let _ = process.kill();
+            let _ = process.wait();
+        }
+    }
+}
+
+/// Launches dbus-daemon, Xvfb and pulseaudio in that order. If one of them fails,
+/// the ones already running are stopped again before the error is returned.
+fn launch_services(
+    id: u32,
+    display: &str,
+    size: (u32, u32),
+    pulse_port: u16,
+) -> Result<(StartupGuard, String), Error> {
+    let mut startup = StartupGuard::new(id);
+
+    info!("[Engine({})] Launching dbus-daemon", id);
+    let (dbus, dbus_session) = launch_dbus()?;
+    startup.push("dbus-daemon", dbus);
+    info!("[Engine({})] using dbus_session {:?}", id, dbus_session);
+
+    info!("[Engine({})] Launching Xvfb", id);
+    startup.push("Xvfb", launch_xvfb(&dbus_session, display, size)?);
+
+    info!("[Engine({})] Launching PulseAudio", id);
+    startup.push("pulseaudio", launch_pulse(&dbus_session, pulse_port)?);
 
-        info!("[Engine({})] Launching dbus-daemon", cfg.id);
-        let (dbus, dbus_session) = launch_dbus()?;
-        info!("[Engine({})] using dbus_session {:?}", cfg.id, dbus_session);
+    Ok((startup, dbus_session))
+}
 
-        info!("[Engine({})] Launching Xvfb", cfg.id);
-        let xvfb = launch_xvfb(&dbus_session, display, cfg.size)?;
+impl Drop for Engine {
+    fn drop(&mut self) {
+        if let Some(encoder) = self.gst_encode.take() {
+            let _ = encoder.pipeline.set_state(gst::State::Null);
+        }
+        if let Err(e) = self.teardown() {
+            warn!("[Engine({})] teardown failed: {}", self.id, e);
+        }
+    }
+}
 
-        info!("[Engine({})] Launching PulseAudio", cfg.id);
-        let pulse = launch_pulse(&dbus_session, cfg.id)?;
+impl Engine {
+    pub fn new(cfg: EngineConfig, events: EventSender) -> Result<Engine, Error> {
+        let config = cfg.clone();
+        let resources = cfg
+            .resources
+            .ok_or(format_err!("engine {} has no resources assigned", cfg.id))?;
+        let display = resources.display_name();
+        let pulse_server = resources.pulse_server();
+        let devtools_port = resources.devtools_port;
+
+        let _ = events.unbounded_send((cfg.id, EngineEvent::Starting));
+        let (startup, dbus_session) =
+            launch_services(cfg.id, &display, cfg.size, resources.pulse_port)?;
 
         info!("[Engine({})] Launching Chromium", cfg.id);
-        let (browser, tab) =
-            launch_chromium_browser(display, pulse_server, &cfg.url, &dbus_session)?;
+        let (browser, tab) = launch_chromium_browser(
+            &display,
+            &pulse_server,
+            devtools_port,
+            &cfg.url,
+            &dbus_session,
+        )?;
+        info!(
+            "[Engine({})] Chromium devtools on port {}",
+            cfg.id, devtools_port
+        );
+        watch_navigation(&tab, cfg.id, events.clone());
 
         info!("[Engine({})] Launching Gstreamer Debug", cfg.id);
         let gst_debug = match cfg.gst_debug {
-            true => Some(launch_gstreamer_debug(display, pulse_server)?),
+            true => Some(launch_gstreamer_debug(&display, &pulse_server)?),
             false => None,
         };
 
-        info!("[Engine({})] Launching Gstreamer Encoder", cfg.id);
-        let filepath = format!("{}/recording-{}.mp4", cfg.encode_dir.unwrap(), cfg.id);
-        let gst_encode =
-            launch_gstreamer_encode(display, pulse_server, Some(filepath), cfg.encode_rtmp)?;
-        let (encode_eos_tx, encode_eos_rx) = mpsc::channel::<bool>(1);
+        // Everything launched, from here on Engine::stop owns the teardown
+        let mut processes = startup.finish().into_iter();
+        let dbus = processes.next().unwrap();
+        let xvfb = processes.next().unwrap();
+        let pulse = processes.next().unwrap();
 
-        let encode_bus = gst_encode.bus().unwrap();
-        cfg.glib_ctx
-            .spawn(message_handler(encode_bus, encode_eos_tx));
-
-        Ok(Engine {
+        let mut engine = Engine {
             id: cfg.id,
             ctx: cfg.glib_ctx,
+            config: config,
+            started: Instant::now(),
+            run: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |t| t.as_millis()),
+            resources: resources,
+            display: display,
+            pulse_server: pulse_server,
+            encode_dir: cfg.encode_dir,
+            encode_rtmp: cfg.encode_rtmp,
+            profile: cfg.profile,
+            container: cfg.container,
+            segment_max_duration: cfg.segment_max_duration,
+            segment_max_size: cfg.segment_max_size,
+            fragment_duration: cfg.fragment_duration,
+            hls: cfg.hls_dir.map(|dir| HlsOutput {
+                dir: format!("{}/{}", dir, cfg.id),
+                playlist_length: cfg.hls_playlist_length,
+                segment_duration: cfg.hls_segment_duration,
+            }),
+            encode_count: 0,
+            drain_timeout: Duration::from_secs(cfg.drain_timeout),
+            idle_timeout: cfg.idle_timeout.map(Duration::from_secs),
+            silence_threshold: cfg.silence_threshold,
+            stopped: false,
+            events: events,
+            supervise_policy: cfg.supervise_policy,
+            max_restarts: cfg.max_restarts,
+            restarts: 0,
+            ignored: HashSet::new(),
+            dbus_session: dbus_session,
             dbus: dbus,
             xvfb: xvfb,
             pulse: pulse,
             browser: Some(browser),
             //        tab: tab,
-            gst_encode: gst_encode,
-            gst_encode_eos_rx: encode_eos_rx,
+            gst_encode: None,
             gst_debug: gst_debug,
+            gst_previews: HashMap::new(),
+            preview_count: 0,
+        };
+
+        // Dropping the engine on failure tears down everything launched above
+        if cfg.encode_enabled {
+            engine.start_encode()?;
+        }
+
+        Ok(engine)
+    }
+
+    pub fn status(&self) -> EngineStatus {
+        let encoder = self.gst_encode.as_ref();
+        let output = encoder.and_then(|e| e.output.clone());
+
+        EngineStatus {
+            id: self.id,
+            state: EngineState::Running,
+            config: self.config.clone(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            pipeline_state: encoder.map(|e| format!("{:?}", e.pipeline.current_state())),
+            paused: self.is_paused(),
+            bytes_written: output.as_deref().map_or(0, output_bytes),
+            output: output,
+            pids: EnginePids {
+                dbus: self.dbus.pid(),
+                xvfb: self.xvfb.pid(),
+                pulse: self.pulse.pid(),
+                chromium: self.browser.as_ref().and_then(|b| b.get_process_id()),
+            },
+        }
+    }
+
+    fn emit(&self, event: EngineEvent) {
+        let _ = self.events.unbounded_send((self.id, event));
+    }
+
+    /// Checks the engine's subprocesses are still alive and applies the supervise
+    /// policy to the first one that isn't. Returns the subprocess to `restart`, if
+    /// any. An error means the engine should be failed and stopped.
+    pub fn supervise(&mut self) -> Result<Option<Subprocess>, Error> {
+        let (process, status) = match self.find_exited() {
+            Some(exited) => exited,
+            None => return Ok(None),
+        };
+        warn!("[Engine({})] {:?} exited: {}", self.id, process, status);
+        self.emit(EngineEvent::SubprocessExited { process, status });
+
+        match self.supervise_policy {
+            SupervisePolicy::Ignore => {
+                self.ignored.insert(process);
+                Ok(None)
+            }
+            SupervisePolicy::Fail => Err(format_err!("{:?} exited", process)),
+            SupervisePolicy::Restart if self.restarts >= self.max_restarts => Err(format_err!(
+                "{:?} exited, giving up after {} restarts",
+                process,
+                self.restarts
+            )),
+            SupervisePolicy::Restart => {
+                self.restarts += 1;
+                Ok(Some(process))
+            }
+        }
+    }
+
+    fn find_exited(&mut self) -> Option<(Subprocess, String)> {
+        let processes = vec![
+            (Subprocess::Dbus, &mut self.dbus),
+            (Subprocess::Xvfb, &mut self.xvfb),
+            (Subprocess::Pulse, &mut self.pulse),
+        ];
+        for (process, popen) in processes {
+            if self.ignored.contains(&process) {
+                continue;
+            }
+            if let Some(status) = popen.poll() {
+                return Some((process, format!("{:?}", status)));
+            }
+        }
+
+        if self.ignored.contains(&Subprocess::Chromium) {
+            return None;
+        }
+        // Checked through /proc rather than devtools, which would block the loop
+        match self.browser.as_ref().and_then(|b| b.get_process_id()) {
+            Some(pid) if !process_alive(pid) => {
+                Some((Subprocess::Chromium, format!("pid {} is gone", pid)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Relaunches a subprocess that died, and Chromium along with it. Blocks until
+    /// the page has loaded again, so it's run off the Manager's loop. An encoder
+    /// capturing from a dead Xvfb or pulseaudio has to be stopped beforehand.
+    pub fn restart(&mut self, process: Subprocess) -> Result<(), Error> {
+        info!("[Engine({})] Restarting {:?}", self.id, process);
+
+        // Chromium depends on all the others, so it's relaunched whatever died
+        let _ = self.browser.take();
+
+        match process {
+            Subprocess::Dbus => {
+                let (dbus, dbus_session) = launch_dbus()?;
+                self.dbus = dbus;
+                self.dbus_session = dbus_session;
+            }
+            Subprocess::Xvfb => {
+                // A crashed Xvfb leaves its lock behind and a new one refuses to start
+                let _ = std::fs::remove_file(format!("/tmp/.X{}-lock", self.resources.display));
+                self.xvfb = launch_xvfb(&self.dbus_session, &self.display, self.config.size)?;
+            }
+            Subprocess::Pulse => {
+                self.pulse = launch_pulse(&self.dbus_session, self.resources.pulse_port)?;
+            }
+            Subprocess::Chromium => (),
+        }
+
+        let (browser, tab) = launch_chromium_browser(
+            &self.display,
+            &self.pulse_server,
+            self.resources.devtools_port,
+            &self.config.url,
+            &self.dbus_session,
+        )?;
+        watch_navigation(&tab, self.id, self.events.clone());
+        self.browser = Some(browser);
+
+        self.emit(EngineEvent::SubprocessRestarted { process });
+        Ok(())
+    }
+
+    pub fn resources(&self) -> &Resources {
+        &self.resources
+    }
+
+    pub fn devtools_port(&self) -> u16 {
+        self.resources.devtools_port
+    }
+
+    pub fn is_encoding(&self) -> bool {
+        self.gst_encode.is_some()
+    }
+
+    /// Starts the encoder against the already running display and pulse server.
+    ///
+    /// Files are named after the engine's id and creation time, and every call after
+    /// the first writes to a new file so earlier recordings aren't overwritten.
+    pub fn start_encode(&mut self) -> Result<(), Error> {
+        if self.gst_encode.is_some() {
+            return Err(format_err!("engine {} is already encoding", self.id));
+        }
+
+        let file = self.encode_dir.as_ref().map(|dir| {
+            let base = format!("{}/recording-{}-{}", dir, self.id, self.run);
+            let name = match self.encode_count {
+                0 => base,
+                n => format!("{}-{}", base, n),
+            };
+            let segmented = self.segment_max_duration.is_some() || self.segment_max_size.is_some();
+            let ext = self.container.extension();
+
+            FileOutput {
+                // splitmuxsink numbers the segments through the format string
+                location: match segmented {
+                    true => format!("{}-%05d.{}", name, ext),
+                    false => format!("{}.{}", name, ext),
+                },
+                manifest: match segmented {
+                    true => Some(format!("{}.manifest", name)),
+                    false => None,
+                },
+                segment_max_duration: self.segment_max_duration,
+                segment_max_size: self.segment_max_size,
+                container: self.container,
+                fragment_duration: self.fragment_duration,
+            }
+        });
+        let manifest = file.as_ref().and_then(|f| f.manifest.clone());
+        let output = file.as_ref().map(|f| f.location.clone());
+
+        info!("[Engine({})] Launching Gstreamer Encoder", self.id);
+        let paused = Arc::new(AtomicBool::new(false));
+        let activity = self
+            .idle_timeout
+            .map(|_| Activity::new(self.silence_threshold));
+        let pipeline = launch_gstreamer_encode(
+            &self.display,
+            &self.pulse_server,
+            &self.profile,
+            EncodeOutputs {
+                file,
+                rtmp: self.encode_rtmp.clone(),
+                hls: self.hls.clone(),
+            },
+            paused.clone(),
+            activity.clone(),
+        )?;
+        let (eos_tx, eos_rx) = oneshot::channel();
+
+        let bus = pipeline.bus().unwrap();
+        self.ctx.spawn(message_handler(
+            self.id,
+            pipeline.downgrade(),
+            bus,
+            eos_tx,
+            manifest,
+            activity.clone(),
+            self.events.clone(),
+        ));
+
+        self.emit(EngineEvent::Recording {
+            output: output.clone(),
+        });
+        self.encode_count += 1;
+        self.gst_encode = Some(Encoder {
+            pipeline,
+            eos_rx,
+            output,
+            started: Instant::now(),
+            paused,
+            paused_at: None,
+            pause_offset: 0,
+            activity,
+            idle: false,
+        });
+
+        Ok(())
+    }
+
+    /// Checks the idle watchdog, emitting `Idle` once each time the encoder goes
+    /// `idle_timeout` without sound or a change on screen. Returns whether it has
+    /// just tripped.
+    pub fn check_idle(&mut self) -> bool {
+        let timeout = match self.idle_timeout {
+            Some(timeout) => timeout,
+            None => return false,
+        };
+        let paused = self.is_paused();
+        let encoder = match self.gst_encode.as_mut() {
+            Some(encoder) => encoder,
+            None => return false,
+        };
+        let activity = match &encoder.activity {
+            Some(activity) => activity,
+            None => return false,
+        };
+
+        // Time spent paused doesn't count as idle
+        if paused {
+            activity.touch();
+        }
+        let idle_for = activity.idle_for();
+        if idle_for < timeout {
+            encoder.idle = false;
+            return false;
+        }
+        if encoder.idle {
+            return false;
+        }
+        encoder.idle = true;
+
+        info!(
+            "[Engine({})] no sound or screen change for {:?}",
+            self.id, idle_for
+        );
+        self.emit(EngineEvent::Idle {
+            idle_secs: idle_for.as_secs(),
+        });
+        true
+    }
+
+    /// Directory holding this engine's HLS playlist and segments, if it has one
+    pub fn hls_dir(&self) -> Option<&str> {
+        self.hls.as_ref().map(|hls| hls.dir.as_str())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.gst_encode
+            .as_ref()
+            .map_or(false, |encoder| encoder.paused_at.is_some())
+    }
+
+    /// Stops feeding captured video and audio into the encoder. Everything captured
+    /// until `resume` is dropped.
+    pub fn pause(&mut self) -> Result<(), Error> {
+        let id = self.id;
+        let encoder = self
+            .gst_encode
+            .as_mut()
+            .ok_or(format_err!("engine {} is not encoding", id))?;
+        if encoder.paused_at.is_some() {
+            return Err(format_err!("engine {} is already paused", id));
+        }
+
+        let now = encoder
+            .pipeline
+            .current_running_time()
+            .ok_or(format_err!("engine {} encoder has no running time", id))?;
+
+        info!("[Engine({})] pausing encoder", id);
+        encoder.paused.store(true, Ordering::SeqCst);
+        encoder.paused_at = Some(now.nseconds());
+        self.emit(EngineEvent::Paused);
+
+        Ok(())
+    }
+
+    /// Lets captured video and audio back into the encoder. The gates are offset by
+    /// the total time spent paused so the output timeline stays continuous.
+    pub fn resume(&mut self) -> Result<(), Error> {
+        let id = self.id;
+        let encoder = self
+            .gst_encode
+            .as_mut()
+            .ok_or(format_err!("engine {} is not encoding", id))?;
+        let paused_at = encoder
+            .paused_at
+            .ok_or(format_err!("engine {} is not paused", id))?;
+
+        let now = encoder
+            .pipeline
+            .current_running_time()
+            .ok_or(format_err!("engine {} encoder has no running time", id))?;
+        encoder.pause_offset += now.nseconds().saturating_sub(paused_at);
+
+        for name in &[VIDEO_GATE_NAME, AUDIO_GATE_NAME] {
+            let pad = encoder
+                .pipeline
+                .by_name(name)
+                .and_then(|gate| gate.static_pad("src"))
+                .ok_or(format_err!("encoder has no {} src pad", name))?;
+            pad.set_offset(-(encoder.pause_offset as i64));
+        }
+
+        info!(
+            "[Engine({})] resuming encoder, {}ns paused in total",
+            id, encoder.pause_offset
+        );
+        encoder.paused_at = None;
+        encoder.paused.store(false, Ordering::SeqCst);
+        self.emit(EngineEvent::Resumed);
+
+        Ok(())
+    }
+
+    /// Takes the encoder out of the engine and sends it an EOS. The returned future
+    /// waits for the EOS to drain so the output is finalized, forcing the encoder
+    /// down once the drain timeout passes. The browser keeps running and encoding
+    /// can be started again right away.
+    pub fn stop_encode(
+        &mut self,
+    ) -> Result<impl Future<Output = Result<StopReport, Error>> + Send + 'static, Error> {
+        let mut encoder = self
+            .gst_encode
+            .take()
+            .ok_or(format_err!("engine {} is not encoding", self.id))?;
+
+        info!("[Engine({})] send eos", self.id);
+        encoder.pipeline.send_event(gst::event::Eos::new());
+
+        let id = self.id;
+        let events = self.events.clone();
+        let drain_timeout = self.drain_timeout;
+        Ok(async move {
+            let timeout = glib::timeout_future(drain_timeout.as_millis() as u32);
+            let finalized = match future::select(&mut encoder.eos_rx, timeout).await {
+                future::Either::Left((eos, _)) => eos.is_ok(),
+                future::Either::Right(_) => false,
+            };
+            match finalized {
+                true => info!("[Engine({})] eos received on bus..gst finished", id),
+                false => warn!(
+                    "[Engine({})] encoder didn't drain within {:?}, forcing it down",
+                    id, drain_timeout
+                ),
+            }
+            encoder.pipeline.set_state(gst::State::Null)?;
+
+            let paused = Duration::from_nanos(encoder.pause_offset);
+            let finished = EngineEvent::Finished {
+                bytes: encoder.output.as_deref().map_or(0, output_bytes),
+                output: encoder.output,
+                duration_secs: encoder
+                    .started
+                    .elapsed()
+                    .saturating_sub(paused)
+                    .as_secs_f64(),
+                finalized,
+            };
+            let _ = events.unbounded_send((id, finished));
+
+            Ok(StopReport { finalized })
         })
     }
 
@@ -114,35 +1217,75 @@ impl Engine {
     //    Ok(())
     //}
 
-    pub fn stop(&mut self) -> Result<(), Error> {
-        let rx = &mut self.gst_encode_eos_rx;
-        // End of stream handler
-        info!("[Engine({})] send eos", self.id);
-        self.gst_encode.send_event(gst::event::Eos::new());
-        self.ctx.block_on(async {
-            rx.next().await;
-        });
+    /// Starts a webrtc preview for a viewer's SDP offer. Returns the preview session
+    /// id and a receiver for the SDP answer.
+    pub fn start_preview(
+        &mut self,
+        offer: &str,
+    ) -> Result<(u32, oneshot::Receiver<String>), Error> {
+        info!("[Engine({})] Launching Gstreamer Preview", self.id);
+        let (pipeline, answer) =
+            launch_gstreamer_preview(&self.display, &self.pulse_server, offer)?;
+
+        let session = self.preview_count;
+        self.preview_count += 1;
+        self.gst_previews.insert(session, pipeline);
+
+        Ok((session, answer))
+    }
+
+    pub fn has_preview(&self, session: u32) -> bool {
+        self.gst_previews.contains_key(&session)
+    }
+
+    pub fn stop_preview(&mut self, session: u32) -> Result<(), Error> {
+        let pipeline = self.gst_previews.remove(&session).ok_or(format_err!(
+            "engine {} has no preview {}",
+            self.id,
+            session
+        ))?;
+
+        info!("[Engine({})] stopping preview {}", self.id, session);
+        pipeline.set_state(gst::State::Null)?;
+
+        Ok(())
+    }
+
+    /// Stops the encoder, if there is one, letting it drain. The rest of the engine
+    /// is torn down once it's dropped, which blocks until its subprocesses exit.
+    pub async fn drain(&mut self) -> Result<StopReport, Error> {
+        match self.is_encoding() {
+            true => self.stop_encode()?.await,
+            false => Ok(StopReport { finalized: true }),
+        }
+    }
+
+    fn teardown(&mut self) -> Result<(), Error> {
+        if self.stopped {
+            return Ok(());
+        }
+        self.stopped = true;
+
+        for (_, pipeline) in self.gst_previews.drain() {
+            pipeline.set_state(gst::State::Null)?;
+        }
 
-        info!("eos received on bus..gst finished");
         if let Some(gst_debug) = &self.gst_debug {
             gst_debug.set_state(gst::State::Null)?;
         }
-        self.gst_encode.set_state(gst::State::Null)?;
 
         let _ = self.browser.take();
 
-        self.xvfb.terminate()?;
-        self.xvfb.wait()?;
+        shut_down(&mut self.xvfb);
         info!("killed xvfb");
 
-        self.pulse.terminate()?;
-        self.pulse.wait()?;
+        shut_down(&mut self.pulse);
         info!("killed pulse");
 
-        self.dbus.kill()?;
-        self.dbus.wait()?;
+        shut_down(&mut self.dbus);
         info!("killed dbus-daemon");
 
+        self.emit(EngineEvent::Stopped);
         Ok(())
     }
 }
@@ -150,6 +1293,7 @@ impl Engine {
 fn launch_chromium_browser(
     display: &str,
     pulse_server: &str,
+    devtools_port: u16,
     recording_url: &str,
     dbus_session: &str,
 ) -> Result<(Browser, Arc<Tab>), Error> {
@@ -167,6 +1311,8 @@ fn launch_chromium_browser(
     env.insert("DBUS_STARTER_ADDRESS".to_owned(), "".to_owned());
     env.insert("DBUS_STARTER_BUS_TYPE".to_owned(), "".to_owned());
 
+    let devtools_arg = format!("--remote-debugging-port={}", devtools_port);
+
     let mut args = Vec::new();
     args.push(OsStr::new("--enable-audio-output"));
     args.push(OsStr::new("--autoplay-policy=no-user-gesture-required"));
@@ -181,7 +1327,7 @@ fn launch_chromium_browser(
     args.push(OsStr::new("--use-gl=swiftshader"));
     args.push(OsStr::new("--disable-setuid-sandbox"));
     args.push(OsStr::new("--remote-debugging-address=0.0.0.0"));
-    args.push(OsStr::new("--remote-debugging-port=9222"));
+    args.push(OsStr::new(&devtools_arg));
     args.push(OsStr::new("--no-sandbox"));
     args.push(OsStr::new("--enable-logging"));
     args.push(OsStr::new("--start-fullscreen"));
@@ -190,6 +1336,7 @@ fn launch_chromium_browser(
     let options = LaunchOptions::default_builder()
         .headless(false)
         .window_size(Some((1920, 1080)))
+        .port(Some(devtools_port))
         .sandbox(false)
         .idle_browser_timeout(Duration::from_secs(600))
         .process_envs(Some(env))
@@ -208,7 +1355,7 @@ fn launch_chromium_browser(
     Ok((browser, tab))
 }
 
-fn launch_pulse(dbus_session: &str, id: u32) -> Result<Popen, Error> {
+fn launch_pulse(dbus_session: &str, port: u16) -> Result<Popen, Error> {
     let pulse = Exec::cmd("pulseaudio")
         .arg("-n")
         .arg("-vvvv")
@@ -219,8 +1366,8 @@ fn launch_pulse(dbus_session: &str, id: u32) -> Result<Popen, Error> {
         .arg("--realtime=false")
         .arg("--load=module-null-sink sink_name=loopback")
         .arg(format!(
-            "--load=module-native-protocol-tcp port=1{:0>4} auth-anonymous=1",
-            id
+            "--load=module-native-protocol-tcp port={} auth-anonymous=1",
+            port
         ))
         .env("DBUS_SESSION_BUS_ADDRESS", OsStr::new(dbus_session))
         .env("DBUS_SESSION_BUS_PID", OsStr::new(""))
@@ -246,10 +1393,14 @@ pub fn launch_dbus() -> Result<(Popen, String), Error> {
         buf.read_line(&mut dbus_stdout)?;
     }
 
-    let dbus_session = dbus_stdout
-        .lines()
-        .next()
-        .ok_or(format_err!("couldn't extract dbus session from stdout"))?;
+    let dbus_session = match dbus_stdout.lines().next() {
+        Some(session) => session,
+        None => {
+            let _ = dbus.kill();
+            let _ = dbus.wait();
+            return Err(format_err!("couldn't extract dbus session from stdout"));
+        }
+    };
 
     Ok((dbus, dbus_session.to_owned()))
 }
@@ -316,9 +1467,31 @@ fn launch_gstreamer_debug(display: &str, pulse_server: &str) -> Result<gst::Pipe
 fn launch_gstreamer_encode(
     display: &str,
     pulse_server: &str,
-    file: Option<String>,
-    rtmp: Option<String>,
+    profile: &EncodeProfile,
+    outputs: EncodeOutputs,
+    paused: Arc<AtomicBool>,
+    activity: Option<Activity>,
 ) -> Result<gst::Pipeline, Error> {
+    let EncodeOutputs { file, rtmp, hls } = outputs;
+    if file.is_none() && rtmp.is_none() && hls.is_none() {
+        return Err(format_err!("encoder needs a file, rtmp or hls output"));
+    }
+    if rtmp.is_some() && profile.codec != VideoCodec::X264 {
+        return Err(format_err!(
+            "rtmp output needs x264, not {:?}",
+            profile.codec
+        ));
+    }
+    if hls.is_some() && !matches!(profile.codec, VideoCodec::X264 | VideoCodec::X265) {
+        return Err(format_err!(
+            "hls output needs x264 or x265, not {:?}",
+            profile.codec
+        ));
+    }
+    if let Some(file) = &file {
+        file.container.check(profile)?;
+    }
+
     let pipeline = gst::Pipeline::new(None);
 
     let ximagesrc = gst::ElementFactory::make("ximagesrc", None)?;
@@ -328,34 +1501,39 @@ fn launch_gstreamer_encode(
     ximagesrc.set_property_from_str("use-damage", "false");
 
     let caps = gst::Caps::builder("video/x-raw")
-        .field("framerate", gst::Fraction::new(30, 1))
+        .field("framerate", gst::Fraction::new(profile.framerate as i32, 1))
         .build();
     let caps_filter = gst::ElementFactory::make("capsfilter", None)?;
     caps_filter.set_property("caps", &caps)?;
 
-    let video_queue = gst::ElementFactory::make("queue", None)?;
+    let video_queue = gst::ElementFactory::make("queue", Some(VIDEO_GATE_NAME))?;
     let video_convert = gst::ElementFactory::make("videoconvert", None)?;
-    let video_enc = gst::ElementFactory::make("x264enc", None)?;
-    video_enc.set_property_from_str("speed-preset", "ultrafast");
-    video_enc.set_property_from_str("bitrate", "8192");
+    let video_enc = profile.make_video_encoder()?;
 
-    let mux = gst::ElementFactory::make("mp4mux", None)?;
+    let video_tee = gst::ElementFactory::make("tee", Some("video_tee"))?;
+    video_tee.set_property_from_str("allow-not-linked", "true");
 
     let pulsesrc = gst::ElementFactory::make("pulsesrc", None)?;
     pulsesrc.set_property_from_str("server", &pulse_server);
     pulsesrc.set_property_from_str("do-timestamp", "true");
 
-    let audio_queue = gst::ElementFactory::make("queue", None)?;
+    let audio_queue = gst::ElementFactory::make("queue", Some(AUDIO_GATE_NAME))?;
     audio_queue.set_property_from_str("max-size-bytes", "0");
     audio_queue.set_property_from_str("max-size-buffers", "0");
     audio_queue.set_property_from_str("max-size-time", "0");
 
-    let audio_enc = gst::ElementFactory::make("opusenc", None)?;
-    audio_enc.set_property_from_str("bitrate", "128000");
+    let audio_tee = gst::ElementFactory::make("tee", Some("audio_tee"))?;
+    audio_tee.set_property_from_str("allow-not-linked", "true");
 
-    let filesink = gst::ElementFactory::make("filesink", None)?;
-    filesink.set_property_from_str("location", &file.unwrap());
-    filesink.set_property_from_str("sync", "false");
+    // level posts the loudness on the bus for the idle watchdog
+    let level = match activity {
+        Some(_) => {
+            let level = gst::ElementFactory::make("level", None)?;
+            level.set_property_from_str("interval", "500000000");
+            Some(level)
+        }
+        None => None,
+    };
 
     pipeline.add_many(&[
         &ximagesrc,
@@ -363,11 +1541,10 @@ fn launch_gstreamer_encode(
         &video_queue,
         &video_convert,
         &video_enc,
+        &video_tee,
         &pulsesrc,
         &audio_queue,
-        &audio_enc,
-        &mux,
-        &filesink,
+        &audio_tee,
     ])?;
 
     gst::Element::link_many(&[
@@ -376,16 +1553,307 @@ fn launch_gstreamer_encode(
         &video_queue,
         &video_convert,
         &video_enc,
-        &mux,
+        &video_tee,
     ])?;
-    gst::Element::link_many(&[&pulsesrc, &audio_queue, &audio_enc, &mux])?;
-    gst::Element::link_many(&[&mux, &filesink])?;
+    match &level {
+        Some(level) => {
+            pipeline.add(level)?;
+            gst::Element::link_many(&[&pulsesrc, level, &audio_queue, &audio_tee])?;
+        }
+        None => gst::Element::link_many(&[&pulsesrc, &audio_queue, &audio_tee])?,
+    }
+
+    add_pause_probe(&video_queue, paused.clone())?;
+    add_pause_probe(&audio_queue, paused)?;
+    if let Some(activity) = activity {
+        add_frame_probe(&caps_filter, activity)?;
+    }
+
+    if let Some(file) = file {
+        let file_bin = build_file_branch(&file, profile)?;
+        pipeline.add(&file_bin)?;
+        video_tee.link_pads(None, &file_bin, Some("video"))?;
+        audio_tee.link_pads(None, &file_bin, Some("audio"))?;
+    }
+
+    if let Some(location) = rtmp {
+        let rtmp_bin = build_rtmp_branch(&location, profile)?;
+        pipeline.add(&rtmp_bin)?;
+        video_tee.link_pads(None, &rtmp_bin, Some("video"))?;
+        audio_tee.link_pads(None, &rtmp_bin, Some("audio"))?;
+    }
+
+    if let Some(hls) = hls {
+        let hls_bin = build_hls_branch(&hls, profile)?;
+        pipeline.add(&hls_bin)?;
+        video_tee.link_pads(None, &hls_bin, Some("video"))?;
+        audio_tee.link_pads(None, &hls_bin, Some("audio"))?;
+    }
 
     pipeline.set_state(gst::State::Playing)?;
 
     Ok(pipeline)
 }
 
+/// Drops buffers entering `gate` while `paused` is set.
+fn add_pause_probe(gate: &gst::Element, paused: Arc<AtomicBool>) -> Result<(), Error> {
+    let pad = gate
+        .static_pad("sink")
+        .ok_or(format_err!("{} has no sink pad", gate.name()))?;
+    pad.add_probe(
+        gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST,
+        move |_, _| match paused.load(Ordering::SeqCst) {
+            true => gst::PadProbeReturn::Drop,
+            false => gst::PadProbeReturn::Ok,
+        },
+    );
+    Ok(())
+}
+
+/// Counts frames leaving `element` that differ from the one before as activity.
+/// Frames are compared by a hash of every `FRAME_SAMPLE_STRIDE`th byte.
+fn add_frame_probe(element: &gst::Element, activity: Activity) -> Result<(), Error> {
+    let pad = element
+        .static_pad("src")
+        .ok_or(format_err!("{} has no src pad", element.name()))?;
+    let last = AtomicU64::new(0);
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        if let Some(gst::PadProbeData::Buffer(buffer)) = &info.data {
+            if let Ok(map) = buffer.map_readable() {
+                let mut hasher = DefaultHasher::new();
+                map.as_slice()
+                    .iter()
+                    .step_by(FRAME_SAMPLE_STRIDE)
+                    .for_each(|b| hasher.write_u8(*b));
+                let frame = hasher.finish();
+                if last.swap(frame, Ordering::Relaxed) != frame {
+                    activity.touch();
+                }
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+    Ok(())
+}
+
+/// Builds the recording leg of the encoder: video from the video tee and audio
+/// encoded per `profile` from the audio tee are muxed into the configured container
+/// at the output location.
+///
+/// Segmented outputs go through `splitmuxsink`, which finalizes every segment as it
+/// rotates so a crash loses at most the segment being written. Fragmented outputs
+/// stay playable up to the last complete fragment.
+fn build_file_branch(file: &FileOutput, profile: &EncodeProfile) -> Result<gst::Bin, Error> {
+    let bin = gst::Bin::new(Some("file"));
+
+    let video_queue = gst::ElementFactory::make("queue", None)?;
+    let video_parse = profile.make_video_parser()?;
+
+    let audio_queue = gst::ElementFactory::make("queue", None)?;
+    let audio_convert = gst::ElementFactory::make("audioconvert", None)?;
+    let audio_resample = gst::ElementFactory::make("audioresample", None)?;
+    let (audio_enc, audio_parse) = profile.make_audio_encoder()?;
+
+    let mux = file.container.make_muxer()?;
+    if let (Container::Mp4, Some(ms)) = (file.container, file.fragment_duration) {
+        // The moov goes out up front and every fragment is self contained, so
+        // nothing needs to be rewritten when the recording ends
+        mux.set_property("fragment-duration", &ms)?;
+    }
+
+    let mut video_chain = vec![&video_queue];
+    video_chain.extend(video_parse.as_ref());
+    let mut audio_chain = vec![&audio_queue, &audio_convert, &audio_resample, &audio_enc];
+    audio_chain.extend(audio_parse.as_ref());
+
+    bin.add_many(&video_chain)?;
+    bin.add_many(&audio_chain)?;
+    gst::Element::link_many(&video_chain)?;
+    gst::Element::link_many(&audio_chain)?;
+
+    let video_out = video_chain[video_chain.len() - 1];
+    let audio_out = audio_chain[audio_chain.len() - 1];
+
+    if file.is_segmented() {
+        let splitmux = gst::ElementFactory::make("splitmuxsink", None)?;
+        splitmux.set_property("muxer", &mux)?;
+        splitmux.set_property_from_str("location", &file.location);
+        splitmux.set_property_from_str("send-keyframe-requests", "true");
+        if let Some(secs) = file.segment_max_duration {
+            splitmux.set_property("max-size-time", &(secs * 1_000_000_000))?;
+        }
+        if let Some(bytes) = file.segment_max_size {
+            splitmux.set_property("max-size-bytes", &bytes)?;
+        }
+
+        bin.add(&splitmux)?;
+        video_out.link_pads(None, &splitmux, Some("video"))?;
+        audio_out.link_pads(None, &splitmux, Some("audio_%u"))?;
+    } else {
+        let filesink = gst::ElementFactory::make("filesink", None)?;
+        filesink.set_property_from_str("location", &file.location);
+        filesink.set_property_from_str("sync", "false");
+
+        bin.add_many(&[&mux, &filesink])?;
+        gst::Element::link_many(&[video_out, &mux])?;
+        gst::Element::link_many(&[audio_out, &mux])?;
+        gst::Element::link_many(&[&mux, &filesink])?;
+    }
+
+    add_ghost_pad(&bin, &video_queue, "video")?;
+    add_ghost_pad(&bin, &audio_queue, "audio")?;
+
+    Ok(bin)
+}
+
+/// Builds the live streaming leg of the encoder: h264 from the video tee and raw
+/// audio from the audio tee are muxed into flv and pushed to `location`.
+///
+/// FLV can't carry opus, so audio is re-encoded to AAC here. Each input starts with
+/// an `errorignore` so a failing rtmpsink can't push a flow error back through the
+/// tees and stop the file recording.
+fn build_rtmp_branch(location: &str, profile: &EncodeProfile) -> Result<gst::Bin, Error> {
+    let bin = gst::Bin::new(Some(RTMP_BIN_NAME));
+
+    let video_ignore = gst::ElementFactory::make("errorignore", None)?;
+    let video_queue = gst::ElementFactory::make("queue", None)?;
+    let video_parse = gst::ElementFactory::make("h264parse", None)?;
+
+    let audio_ignore = gst::ElementFactory::make("errorignore", None)?;
+    let audio_queue = gst::ElementFactory::make("queue", None)?;
+    let audio_convert = gst::ElementFactory::make("audioconvert", None)?;
+    let audio_resample = gst::ElementFactory::make("audioresample", None)?;
+    let audio_enc = profile::make_aac_encoder()?;
+    audio_enc.set_property_from_str("bitrate", &profile.audio_bitrate.to_string());
+    let audio_parse = gst::ElementFactory::make("aacparse", None)?;
+
+    let mux = gst::ElementFactory::make("flvmux", None)?;
+    mux.set_property_from_str("streamable", "true");
+
+    let rtmpsink = gst::ElementFactory::make("rtmpsink", None)?;
+    rtmpsink.set_property_from_str("location", location);
+    rtmpsink.set_property_from_str("sync", "false");
+
+    bin.add_many(&[
+        &video_ignore,
+        &video_queue,
+        &video_parse,
+        &audio_ignore,
+        &audio_queue,
+        &audio_convert,
+        &audio_resample,
+        &audio_enc,
+        &audio_parse,
+        &mux,
+        &rtmpsink,
+    ])?;
+
+    gst::Element::link_many(&[&video_ignore, &video_queue, &video_parse, &mux])?;
+    gst::Element::link_many(&[
+        &audio_ignore,
+        &audio_queue,
+        &audio_convert,
+        &audio_resample,
+        &audio_enc,
+        &audio_parse,
+        &mux,
+    ])?;
+    gst::Element::link_many(&[&mux, &rtmpsink])?;
+
+    add_ghost_pad(&bin, &video_ignore, "video")?;
+    add_ghost_pad(&bin, &audio_ignore, "audio")?;
+
+    Ok(bin)
+}
+
+/// Exposes the sink pad of `element` on `bin` as `name` so tees can link to it.
+fn add_ghost_pad(bin: &gst::Bin, element: &gst::Element, name: &str) -> Result<(), Error> {
+    let pad = element
+        .static_pad("sink")
+        .ok_or(format_err!("{} has no sink pad", element.name()))?;
+    bin.add_pad(&gst::GhostPad::with_target(Some(name), &pad)?)?;
+    Ok(())
+}
+
+/// Builds the live preview leg of the encoder: h264/h265 and AAC audio are cut into
+/// mpeg-ts segments with a rolling playlist in `hls.dir`.
+fn build_hls_branch(hls: &HlsOutput, profile: &EncodeProfile) -> Result<gst::Bin, Error> {
+    std::fs::create_dir_all(&hls.dir)?;
+
+    let bin = gst::Bin::new(Some("hls"));
+
+    let video_queue = gst::ElementFactory::make("queue", None)?;
+    let video_parse = profile
+        .make_video_parser()?
+        .ok_or(format_err!("hls output needs a parsed video stream"))?;
+
+    let audio_queue = gst::ElementFactory::make("queue", None)?;
+    let audio_convert = gst::ElementFactory::make("audioconvert", None)?;
+    let audio_resample = gst::ElementFactory::make("audioresample", None)?;
+    let audio_enc = profile::make_aac_encoder()?;
+    audio_enc.set_property_from_str("bitrate", &profile.audio_bitrate.to_string());
+    let audio_parse = gst::ElementFactory::make("aacparse", None)?;
+
+    let hlssink = gst::ElementFactory::make("hlssink2", None)?;
+    hlssink.set_property_from_str("location", &format!("{}/segment%05d.ts", hls.dir));
+    hlssink.set_property_from_str("playlist-location", &format!("{}/playlist.m3u8", hls.dir));
+    hlssink.set_property("target-duration", &hls.segment_duration)?;
+    hlssink.set_property("playlist-length", &hls.playlist_length)?;
+    // Keep a couple of segments past the playlist for clients still fetching them
+    hlssink.set_property("max-files", &(hls.playlist_length + 2))?;
+
+    bin.add_many(&[
+        &video_queue,
+        &video_parse,
+        &audio_queue,
+        &audio_convert,
+        &audio_resample,
+        &audio_enc,
+        &audio_parse,
+        &hlssink,
+    ])?;
+
+    gst::Element::link_many(&[&video_queue, &video_parse])?;
+    video_parse.link_pads(None, &hlssink, Some("video"))?;
+    gst::Element::link_many(&[
+        &audio_queue,
+        &audio_convert,
+        &audio_resample,
+        &audio_enc,
+        &audio_parse,
+    ])?;
+    audio_parse.link_pads(None, &hlssink, Some("audio"))?;
+
+    add_ghost_pad(&bin, &video_queue, "video")?;
+    add_ghost_pad(&bin, &audio_queue, "audio")?;
+
+    Ok(bin)
+}
+
+/// Unlinks the rtmp leg from the tees and shuts it down. The bin is removed from the
+/// pipeline so it no longer holds back the EOS that finalizes the file recording.
+fn detach_rtmp_branch(pipeline: &gst::Pipeline) -> Result<(), Error> {
+    let bin = match pipeline.by_name(RTMP_BIN_NAME) {
+        Some(bin) => bin,
+        None => return Ok(()),
+    };
+
+    for (tee_name, ghost_name) in &[("video_tee", "video"), ("audio_tee", "audio")] {
+        let ghost = bin
+            .static_pad(ghost_name)
+            .ok_or(format_err!("rtmp bin has no {} pad", ghost_name))?;
+        if let (Some(tee), Some(tee_pad)) = (pipeline.by_name(tee_name), ghost.peer()) {
+            tee_pad.unlink(&ghost)?;
+            tee.release_request_pad(&tee_pad);
+        }
+    }
+
+    pipeline.remove(&bin)?;
+    bin.set_state(gst::State::Null)?;
+
+    Ok(())
+}
+
 fn x11_list_window_classes(display: &str) -> Result<(), Error> {
     let (conn, screen_num) = x11rb::connect(Some(display))?;
 
@@ -434,17 +1902,90 @@ fn x11_list_window_classes(display: &str) -> Result<(), Error> {
     Ok(())
 }
 
-async fn message_handler(bus: gst::Bus, mut tx: mpsc::Sender<bool>) {
+/// Size of the recording at `location`. Segmented locations are a splitmuxsink
+/// pattern, so every segment matching it is counted. Only the five digit segment
+/// number may differ, later recordings of the engine share the prefix.
+fn output_bytes(location: &str) -> u64 {
+    let path = std::path::Path::new(location);
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let (prefix, suffix) = match name.find("%05d") {
+        Some(i) => (&name[..i], &name[i + 4..]),
+        None => return std::fs::metadata(path).map_or(0, |m| m.len()),
+    };
+
+    let dir = path.parent().unwrap_or_else(|| std::path::Path::new("."));
+    std::fs::read_dir(dir).map_or(0, |entries| {
+        entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                name.strip_prefix(prefix)
+                    .and_then(|rest| rest.strip_suffix(suffix))
+                    .is_some_and(|n| n.len() == 5 && n.bytes().all(|b| b.is_ascii_digit()))
+            })
+            .filter_map(|entry| entry.metadata().ok())
+            .map(|m| m.len())
+            .sum()
+    })
+}
+
+/// Appends a finished segment to the engine's manifest, one location per line.
+fn append_to_manifest(manifest: &str, segment: &str) -> Result<(), Error> {
+    let mut f = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(manifest)?;
+    writeln!(f, "{}", segment)?;
+    Ok(())
+}
+
+/// Reports page navigations of the engine's main frame as `PageNavigated` events.
+fn watch_navigation(tab: &Tab, id: u32, events: EventSender) {
+    let listener = move |event: &ChromeEvent| {
+        if let ChromeEvent::FrameNavigated(navigated) = event {
+            let frame = &navigated.params.frame;
+            if frame.parent_id.is_none() {
+                let url = frame.url.clone();
+                let _ = events.unbounded_send((id, EngineEvent::PageNavigated { url }));
+            }
+        }
+    };
+    if let Err(e) = tab.add_event_listener(Arc::new(listener)) {
+        warn!("[Engine({})] couldn't watch page navigation: {}", id, e);
+    }
+}
+
+async fn message_handler(
+    id: u32,
+    pipeline: gst::glib::WeakRef<gst::Pipeline>,
+    bus: gst::Bus,
+    mut eos_tx: oneshot::Sender<()>,
+    manifest: Option<String>,
+    activity: Option<Activity>,
+    events: EventSender,
+) {
+    let emit = |event| {
+        let _ = events.unbounded_send((id, event));
+    };
     let mut messages = bus.stream();
 
-    while let Some(msg) = messages.next().await {
+    loop {
         use gst::MessageView;
 
+        // The bus is flushed on the way to Null, so a pipeline forced down without
+        // an EOS never says so. Its encoder being dropped ends the handler instead.
+        let msg = match future::select(messages.next(), eos_tx.cancellation()).await {
+            future::Either::Left((Some(msg), _)) => msg,
+            _ => return,
+        };
+
         // Determine whether we want to quit: on EOS or error message
         // we quit, otherwise simply continue.
         match msg.view() {
             MessageView::Eos(..) => {
-                tx.start_send(true).unwrap();
+                emit(EngineEvent::Eos);
+                let _ = eos_tx.send(());
                 return;
             }
             MessageView::Error(err) => {
@@ -454,8 +1995,288 @@ async fn message_handler(bus: gst::Bus, mut tx: mpsc::Sender<bool>) {
                     err.error(),
                     err.debug()
                 );
+
+                // A failing rtmp leg only takes itself down, the file keeps recording
+                let mut in_rtmp_branch = false;
+                if let (Some(pipeline), Some(src)) = (pipeline.upgrade(), err.src()) {
+                    in_rtmp_branch = pipeline
+                        .by_name(RTMP_BIN_NAME)
+                        .map_or(false, |bin| src.has_as_ancestor(&bin));
+                    if in_rtmp_branch {
+                        warn!("rtmp stream failed, detaching it from the encoder");
+                        if let Err(e) = detach_rtmp_branch(&pipeline) {
+                            warn!("couldn't detach rtmp stream: {}", e);
+                        }
+                    }
+                }
+
+                emit(EngineEvent::Error {
+                    source: err.src().map(|s| s.path_string().to_string()),
+                    message: err.error().to_string(),
+                    fatal: !in_rtmp_branch,
+                });
+            }
+            MessageView::Element(elem) => {
+                let s = match elem.structure() {
+                    Some(s) if s.name() == "level" => {
+                        if let Some(activity) = &activity {
+                            activity.hear(s);
+                        }
+                        continue;
+                    }
+                    Some(s) if s.name() == "splitmuxsink-fragment-closed" => s,
+                    _ => continue,
+                };
+                let segment = match s.get::<String>("location") {
+                    Ok(segment) => segment,
+                    Err(_) => continue,
+                };
+                info!("segment closed: {}", segment);
+                if let Some(manifest) = &manifest {
+                    if let Err(e) = append_to_manifest(manifest, &segment) {
+                        warn!("couldn't update manifest {}: {}", manifest, e);
+                    }
+                }
+                emit(EngineEvent::SegmentClosed { location: segment });
             }
             _ => (),
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+
+    // The launch functions find their binaries on PATH, which is process wide
+    static PATH_LOCK: Mutex<()> = Mutex::new(());
+
+    /// A fresh directory of stub binaries, each of which logs its name to the
+    /// returned log file when it gets SIGTERM.
+    fn stubs(test: &str, names: &[&str]) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("tapedeck-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("terminated.log");
+
+        for name in names {
+            let mut script = format!(
+                "#!/bin/sh\nPATH=/usr/bin:/bin\ntrap 'echo {} >> {}; exit 0' TERM\n",
+                name,
+                log.display()
+            );
+            if *name == "dbus-daemon" {
+                script.push_str("echo unix:path=/tmp/tapedeck-test-bus\n");
+            }
+            script.push_str(&format!(
+                "touch {}\nwhile true; do sleep 0.05; done\n",
+                dir.join(format!("{}.ready", name)).display()
+            ));
+
+            let path = dir.join(name);
+            fs::write(&path, script).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        (dir, log)
+    }
+
+    #[test]
+    fn output_bytes_counts_only_this_recordings_segments() {
+        let dir = std::env::temp_dir().join(format!("tapedeck-bytes-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, len) in [
+            ("recording-1-00000.mp4", 3),
+            ("recording-1-00001.mp4", 4),
+            ("recording-1-1-00000.mp4", 100),
+            ("recording-1-0000.mp4", 100),
+            ("recording-1-00000.mkv", 100),
+        ] {
+            fs::write(dir.join(name), vec![0; len]).unwrap();
+        }
+
+        let location = dir.join("recording-1-%05d.mp4");
+        assert_eq!(output_bytes(location.to_str().unwrap()), 7);
+        let single = dir.join("recording-1-1-00000.mp4");
+        assert_eq!(output_bytes(single.to_str().unwrap()), 100);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    pub(crate) fn with_path<T>(dir: &Path, f: impl FnOnce() -> T) -> T {
+        let _lock = PATH_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = std::env::var_os("PATH");
+        std::env::set_var("PATH", dir);
+        let result = f();
+        match path {
+            Some(path) => std::env::set_var("PATH", path),
+            None => std::env::remove_var("PATH"),
+        }
+        result
+    }
+
+    fn wait_ready(dir: &Path, name: &str) {
+        let ready = dir.join(format!("{}.ready", name));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !ready.exists() {
+            assert!(Instant::now() < deadline, "{} stub never started", name);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn terminated(log: &Path) -> Vec<String> {
+        fs::read_to_string(log)
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    /// Command lines of the processes still running a stub out of `dir`
+    fn still_running(dir: &Path) -> Vec<String> {
+        let dir = dir.to_string_lossy().into_owned();
+        fs::read_dir("/proc")
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| fs::read(entry.path().join("cmdline")).ok())
+            .map(|cmdline| String::from_utf8_lossy(&cmdline).replace('\0', " "))
+            .filter(|cmdline| cmdline.contains(&dir))
+            .collect()
+    }
+
+    #[test]
+    fn guard_stops_processes_in_reverse_order() {
+        let names = ["dbus-daemon", "Xvfb", "pulseaudio"];
+        let (dir, log) = stubs("guard-order", &names);
+
+        let mut guard = StartupGuard::new(1);
+        for name in &names {
+            let process = Exec::cmd(dir.join(name)).popen().unwrap();
+            wait_ready(&dir, name);
+            guard.push(name, process);
+        }
+        drop(guard);
+
+        assert_eq!(terminated(&log), vec!["pulseaudio", "Xvfb", "dbus-daemon"]);
+        assert!(still_running(&dir).is_empty());
+    }
+
+    #[test]
+    fn dbus_failure_leaves_nothing_running() {
+        let (dir, log) = stubs("dbus-fails", &[]);
+
+        let launched = with_path(&dir, || launch_services(1, ":99", (640, 480), 4713));
+
+        assert!(launched.is_err());
+        assert!(terminated(&log).is_empty());
+        assert!(still_running(&dir).is_empty());
+    }
+
+    #[test]
+    fn xvfb_failure_stops_dbus() {
+        let (dir, log) = stubs("xvfb-fails", &["dbus-daemon"]);
+
+        let launched = with_path(&dir, || launch_services(1, ":99", (640, 480), 4713));
+
+        assert!(launched.is_err());
+        assert_eq!(terminated(&log), vec!["dbus-daemon"]);
+        assert!(still_running(&dir).is_empty());
+    }
+
+    #[test]
+    fn pulse_failure_stops_xvfb_and_dbus() {
+        let (dir, log) = stubs("pulse-fails", &["dbus-daemon", "Xvfb"]);
+
+        let launched = with_path(&dir, || launch_services(1, ":99", (640, 480), 4713));
+
+        assert!(launched.is_err());
+        // Xvfb may be stopped before its stub got to install the trap, but it
+        // always goes before dbus
+        assert_eq!(
+            terminated(&log).last().map(String::as_str),
+            Some("dbus-daemon")
+        );
+        assert!(still_running(&dir).is_empty());
+    }
+
+    #[test]
+    fn chromium_failure_stops_every_service() {
+        let services = ["dbus-daemon", "Xvfb", "pulseaudio"];
+        let (dir, log) = stubs("chromium-fails", &services);
+        // Found through PATH like a real chromium, it hands out a devtools url
+        // nothing listens on once the services' traps are in place
+        let mut script = "#!/bin/sh\nPATH=/usr/bin:/bin\n".to_owned();
+        for name in &services {
+            let ready = dir.join(format!("{}.ready", name));
+            script.push_str(&format!(
+                "while [ ! -e {} ]; do sleep 0.05; done\n",
+                ready.display()
+            ));
+        }
+        let devtools = "DevTools listening on ws://127.0.0.1:1/devtools/browser/stub";
+        script.push_str(&format!("echo '{}' >&2\nexit 1\n", devtools));
+        let chromium = dir.join("chromium");
+        fs::write(&chromium, script).unwrap();
+        fs::set_permissions(&chromium, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let cfg = EngineConfigBuilder::default()
+            .glib_ctx(glib::MainContext::default())
+            .resources(Some(Resources {
+                display: 99,
+                pulse_port: 4713,
+                devtools_port: 9222,
+            }))
+            .build()
+            .unwrap();
+        let (events, _) = mpsc::unbounded();
+        let engine = with_path(&dir, || Engine::new(cfg, events));
+
+        assert!(engine.is_err());
+        assert_eq!(terminated(&log), vec!["pulseaudio", "Xvfb", "dbus-daemon"]);
+        assert!(still_running(&dir).is_empty());
+    }
+
+    #[test]
+    fn zombies_are_not_alive() {
+        assert!(process_alive(std::process::id()));
+
+        // By path, the PATH of the startup tests may be swapped out meanwhile
+        let mut child = Exec::cmd("/bin/true").popen().unwrap();
+        let pid = child.pid().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        // Exited but not reaped yet
+        while process_alive(pid) {
+            assert!(Instant::now() < deadline, "pid {} never exited", pid);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        child.wait().unwrap();
+        assert!(!process_alive(pid));
+    }
+
+    #[test]
+    fn finished_guard_leaves_processes_running() {
+        let names = ["dbus-daemon"];
+        let (dir, log) = stubs("guard-finish", &names);
+
+        let mut guard = StartupGuard::new(1);
+        guard.push(
+            "dbus-daemon",
+            Exec::cmd(dir.join(names[0])).popen().unwrap(),
+        );
+        wait_ready(&dir, names[0]);
+        let mut processes = guard.finish();
+
+        assert!(terminated(&log).is_empty());
+        assert_eq!(still_running(&dir).len(), 1);
+
+        for process in &mut processes {
+            let _ = process.kill();
+            let _ = process.wait();
+        }
+    }
+}
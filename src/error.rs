@@ -0,0 +1,153 @@
+use futures::channel::oneshot;
+use rocket::http::Status;
+use rocket::response::{self, status, Responder};
+use rocket::serde::json::Json;
+use rocket::Request;
+use serde::Serialize;
+use std::fmt;
+
+use crate::engine::ConfigError;
+
+/// Why the Manager couldn't carry out a `ManagerEvent`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ManagerError {
+    /// No engine is running with this id
+    EngineNotFound(u32),
+
+    /// An engine is already running with this id
+    EngineExists(u32),
+
+    /// No recording is scheduled with this id
+    JobNotFound(u32),
+
+    /// The engine has no preview session with this id
+    SessionNotFound(u32, u32),
+
+    /// The engine exists but doesn't have what was asked for, like an hls output
+    Unavailable(String),
+
+    /// The engine isn't in a state the request makes sense in, like pausing an
+    /// engine that isn't encoding
+    Conflict(String),
+
+    /// Anything that went wrong inside the engine itself
+    Internal(String),
+}
+
+impl ManagerError {
+    /// Stable, machine readable name for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ManagerError::EngineNotFound(_) => "engine_not_found",
+            ManagerError::EngineExists(_) => "engine_exists",
+            ManagerError::JobNotFound(_) => "job_not_found",
+            ManagerError::SessionNotFound(..) => "session_not_found",
+            ManagerError::Unavailable(_) => "unavailable",
+            ManagerError::Conflict(_) => "conflict",
+            ManagerError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl fmt::Display for ManagerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ManagerError::EngineNotFound(id) => write!(f, "no engine found id={}", id),
+            ManagerError::EngineExists(id) => write!(f, "engine {} is already running", id),
+            ManagerError::JobNotFound(id) => write!(f, "no recording scheduled id={}", id),
+            ManagerError::SessionNotFound(id, session) => {
+                write!(f, "no preview session {} on engine {}", session, id)
+            }
+            ManagerError::Unavailable(msg)
+            | ManagerError::Conflict(msg)
+            | ManagerError::Internal(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl From<failure::Error> for ManagerError {
+    fn from(err: failure::Error) -> Self {
+        ManagerError::Internal(err.to_string())
+    }
+}
+
+/// Error returned by the REST API, rendered as `{"code": .., "message": ..}` with
+/// a matching HTTP status.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Status,
+    pub code: &'static str,
+    pub message: String,
+
+    /// Per field problems with the request body, if that's what was wrong
+    pub fields: Vec<ConfigError>,
+}
+
+#[derive(Serialize)]
+struct ApiErrorBody<'a> {
+    code: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    fields: &'a [ConfigError],
+}
+
+impl ApiError {
+    pub fn new(status: Status, code: &'static str, message: String) -> Self {
+        ApiError {
+            status,
+            code,
+            message,
+            fields: vec![],
+        }
+    }
+
+    pub fn bad_request(message: String) -> Self {
+        ApiError::new(Status::BadRequest, "bad_request", message)
+    }
+
+    pub fn invalid_config(fields: Vec<ConfigError>) -> Self {
+        ApiError {
+            fields,
+            ..ApiError::new(
+                Status::UnprocessableEntity,
+                "invalid_config",
+                "the engine config is invalid".to_owned(),
+            )
+        }
+    }
+
+    pub fn internal(message: String) -> Self {
+        ApiError::new(Status::InternalServerError, "internal", message)
+    }
+}
+
+impl From<ManagerError> for ApiError {
+    fn from(err: ManagerError) -> Self {
+        let status = match err {
+            ManagerError::EngineNotFound(_)
+            | ManagerError::JobNotFound(_)
+            | ManagerError::SessionNotFound(..)
+            | ManagerError::Unavailable(_) => Status::NotFound,
+            ManagerError::EngineExists(_) | ManagerError::Conflict(_) => Status::Conflict,
+            ManagerError::Internal(_) => Status::InternalServerError,
+        };
+        ApiError::new(status, err.code(), err.to_string())
+    }
+}
+
+impl From<oneshot::Canceled> for ApiError {
+    fn from(_: oneshot::Canceled) -> Self {
+        ApiError::internal("manager dropped the request".to_owned())
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let body = ApiErrorBody {
+            code: self.code,
+            message: &self.message,
+            fields: &self.fields,
+        };
+        status::Custom(self.status, Json(body)).respond_to(req)
+    }
+}
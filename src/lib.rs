@@ -3,52 +3,951 @@ extern crate rocket;
 #[macro_use]
 extern crate derive_builder;
 
-use futures::channel::oneshot;
-use std::collections::HashMap;
+use futures::channel::{mpsc, oneshot};
+use futures::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 pub mod engine;
+pub mod error;
+mod preview;
+pub mod profile;
+pub mod resources;
+pub mod transcode;
+mod webhook;
+
+use error::ManagerError;
+
+/// How often the Manager checks on every engine's subprocesses
+const SUPERVISE_INTERVAL_SECS: u32 = 2;
+
+/// How often the Manager starts scheduled engines and stops expired ones
+const SCHEDULE_INTERVAL_SECS: u32 = 1;
+
+/// How long a webrtc preview gets to gather ICE candidates and answer its offer
+const PREVIEW_ANSWER_TIMEOUT_MS: u32 = 10_000;
 
 pub enum ManagerEvent {
-    EngineSpawn(oneshot::Sender<Result<(), String>>, engine::EngineConfig),
-    EngineStop(oneshot::Sender<Result<(), String>>, u32),
+    EngineSpawn(
+        oneshot::Sender<Result<(), ManagerError>>,
+        engine::EngineConfig,
+    ),
+    EngineStop(
+        oneshot::Sender<Result<engine::StopReport, ManagerError>>,
+        u32,
+    ),
+    EncodeStart(oneshot::Sender<Result<(), ManagerError>>, u32),
+    EncodeStop(
+        oneshot::Sender<Result<engine::StopReport, ManagerError>>,
+        u32,
+    ),
+    EnginePause(oneshot::Sender<Result<(), ManagerError>>, u32),
+    EngineResume(oneshot::Sender<Result<(), ManagerError>>, u32),
+    EngineHlsDir(oneshot::Sender<Result<String, ManagerError>>, u32),
+    PreviewStart(
+        oneshot::Sender<Result<(u32, String), ManagerError>>,
+        u32,
+        String,
+    ),
+    PreviewStop(oneshot::Sender<Result<(), ManagerError>>, u32, u32),
+    EngineDevtools(oneshot::Sender<Result<u16, ManagerError>>, u32),
+    EngineList(oneshot::Sender<Vec<engine::EngineStatus>>),
+    EngineStatus(
+        oneshot::Sender<Result<engine::EngineStatus, ManagerError>>,
+        u32,
+    ),
+
+    /// Streams the events of an engine until it stops
+    EngineSubscribe(
+        oneshot::Sender<Result<mpsc::UnboundedReceiver<engine::EngineEvent>, ManagerError>>,
+        u32,
+    ),
+
+    /// Holds an engine back until its `start_at`
+    EngineSchedule(
+        oneshot::Sender<Result<(), ManagerError>>,
+        engine::EngineConfig,
+    ),
+
+    /// Configs of the engines waiting for their `start_at`, soonest first
+    ScheduleList(oneshot::Sender<Vec<engine::EngineConfig>>),
+
+    ScheduleCancel(oneshot::Sender<Result<(), ManagerError>>, u32),
+
+    /// Stops every engine, giving their encoders up to the deadline to drain so
+    /// the recordings are finalized. Answered once they have all ended and their
+    /// final events and webhooks are out, or at the deadline
+    ShutdownAll(oneshot::Sender<()>, Duration),
+
+    /// Sent once the webhooks of every ended engine are done delivering
+    ShutdownFlushed,
+
+    /// Sent by the Manager's own timer when a `ShutdownAll` runs out of time
+    ShutdownDeadline,
+
+    /// Sent once `Engine::new` has returned for a spawned engine
+    EngineStarted(
+        u32,
+        Result<engine::Engine, failure::Error>,
+        oneshot::Sender<Result<(), ManagerError>>,
+    ),
+
+    /// Sent once a dead subprocess of an engine has been relaunched, or failed to
+    /// be. `true` if the encoder was drained for it and should start over.
+    EngineRestarted(u32, engine::Engine, Result<bool, failure::Error>),
+
+    /// Sent once a stopped engine has shut down and its resources can be reused
+    EngineStopped(u32, resources::Resources),
+
+    /// Raised by an engine, forwarded by the Manager to itself
+    EngineEvent(u32, engine::EngineEvent),
+
+    /// Sent by the Manager's own timer to check on the engines' subprocesses
+    Supervise,
+
+    /// Sent by the Manager's own timer to start engines whose `start_at` has come
+    /// and stop those past their `max_duration` or gone idle
+    Schedule,
+}
+
+/// Stops every engine, giving them up to `deadline` to finalize their outputs.
+/// Blocks until the Manager is done, so it must not be called from the thread
+/// running the Manager's main loop.
+pub fn shutdown_all(manager: &glib::Sender<ManagerEvent>, deadline: Duration) {
+    let (tx, rx) = oneshot::channel();
+    if manager
+        .send(ManagerEvent::ShutdownAll(tx, deadline))
+        .is_ok()
+    {
+        let _ = futures::executor::block_on(rx);
+    }
+}
+
+/// A `ShutdownAll` in progress.
+struct Shutdown {
+    res: oneshot::Sender<()>,
+    deadline: Duration,
+    /// Whether every engine has ended and the webhooks are being waited on
+    flushing: bool,
+}
+
+/// Once every engine has ended and its last events have been handled, waits on
+/// the webhooks from a thread and tells the Manager when they're delivered.
+fn flush_if_ended(
+    shutdown: &mut Option<Shutdown>,
+    engines: &HashMap<u32, EngineEntry>,
+    awaiting: &HashSet<u32>,
+    closing_hooks: &mut Vec<JoinHandle<()>>,
+    manager: &glib::Sender<ManagerEvent>,
+) {
+    let shutdown = match shutdown {
+        Some(shutdown) if !shutdown.flushing => shutdown,
+        _ => return,
+    };
+    if !awaiting.is_empty()
+        || engines
+            .values()
+            .any(|entry| !matches!(entry.slot, Slot::Stopped))
+    {
+        return;
+    }
+
+    shutdown.flushing = true;
+    let hooks = std::mem::take(closing_hooks);
+    let manager = manager.clone();
+    std::thread::spawn(move || {
+        for hook in hooks {
+            let _ = hook.join();
+        }
+        let _ = manager.send(ManagerEvent::ShutdownFlushed);
+    });
+}
+
+/// An engine as tracked by the Manager.
+struct EngineEntry {
+    config: engine::EngineConfig,
+    slot: Slot,
+
+    /// When the engine is stopped for running past `max_duration`
+    deadline: Option<Instant>,
+}
+
+enum Slot {
+    Starting,
+    Running(engine::Engine),
+    Restarting,
+    Stopping,
+    Stopped,
+}
+
+impl EngineEntry {
+    fn state(&self) -> engine::EngineState {
+        match self.slot {
+            Slot::Starting => engine::EngineState::Starting,
+            Slot::Running(_) => engine::EngineState::Running,
+            Slot::Restarting => engine::EngineState::Restarting,
+            Slot::Stopping => engine::EngineState::Stopping,
+            Slot::Stopped => engine::EngineState::Stopped,
+        }
+    }
+
+    fn status(&self) -> engine::EngineStatus {
+        match &self.slot {
+            Slot::Running(e) => e.status(),
+            _ => engine::EngineStatus::idle(&self.config, self.state()),
+        }
+    }
+
+    /// Takes the engine out if it's running and stops it in the background.
+    fn stop(&mut self, manager: &glib::Sender<ManagerEvent>) {
+        match std::mem::replace(&mut self.slot, Slot::Stopping) {
+            Slot::Running(e) => {
+                let stopped = stop_engine(self.config.id, e, manager.clone());
+                glib::MainContext::ref_thread_default().spawn_local(async move {
+                    let _ = stopped.await;
+                });
+            }
+            slot => self.slot = slot,
+        }
+    }
+}
+
+/// Whether an engine with this id is starting, running or stopping.
+fn is_live(engines: &HashMap<u32, EngineEntry>, id: u32) -> bool {
+    engines
+        .get(&id)
+        .map_or(false, |entry| !matches!(entry.slot, Slot::Stopped))
+}
+
+/// Reports a recording that was turned down before its engine got a webhook of
+/// its own to the config's webhook, if it has one.
+fn report_unstarted(
+    cfg: &engine::EngineConfig,
+    err: &ManagerError,
+    closing_hooks: &mut Vec<JoinHandle<()>>,
+) {
+    if let Some(url) = cfg.webhook_url.clone() {
+        let hook = webhook::Webhook::new(url, cfg.webhook_secret.clone());
+        hook.send(webhook::WebhookPayload::failed(cfg.id, err.to_string()));
+        closing_hooks.retain(|hook| !hook.is_finished());
+        closing_hooks.push(hook.close());
+    }
+}
+
+/// Forgets an engine once it has shut down and its Stopped event has been handled,
+/// so stopped engines don't pile up and their freed resources aren't reported.
+fn evict_if_ended(engines: &mut HashMap<u32, EngineEntry>, awaiting: &HashSet<u32>, id: u32) {
+    if !is_live(engines, id) && !awaiting.contains(&id) {
+        engines.remove(&id);
+    }
+}
+
+/// The running engine with this id, or why there isn't one.
+fn running(
+    engines: &mut HashMap<u32, EngineEntry>,
+    id: u32,
+) -> Result<&mut engine::Engine, ManagerError> {
+    match engines.get_mut(&id) {
+        None => Err(ManagerError::EngineNotFound(id)),
+        Some(EngineEntry {
+            slot: Slot::Running(e),
+            ..
+        }) => Ok(e),
+        Some(entry) => Err(ManagerError::Conflict(format!(
+            "engine {} is {:?}",
+            id,
+            entry.state()
+        ))),
+    }
+}
+
+/// Runs `Engine::new` on a thread of its own, since launching the subprocesses
+/// and loading the page blocks, then hands the engine back to the Manager.
+fn start_engine(
+    cfg: engine::EngineConfig,
+    events: engine::EventSender,
+    manager: glib::Sender<ManagerEvent>,
+    res: oneshot::Sender<Result<(), ManagerError>>,
+) {
+    let id = cfg.id;
+    let (tx, rx) = oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(engine::Engine::new(cfg, events));
+    });
+
+    glib::MainContext::ref_thread_default().spawn_local(async move {
+        let started = rx.await.unwrap_or_else(|_| {
+            Err(failure::format_err!(
+                "engine {} panicked while starting",
+                id
+            ))
+        });
+        let _ = manager.send(ManagerEvent::EngineStarted(id, started, res));
+    });
+}
+
+/// Relaunches a dead subprocess of an engine taken out of the Manager's map. An
+/// encoder capturing from the dead process is drained first so its recording is
+/// finalized and reported, then the relaunch runs on a thread of its own.
+async fn restart_engine(
+    id: u32,
+    mut engine: engine::Engine,
+    process: engine::Subprocess,
+    manager: glib::Sender<ManagerEvent>,
+) {
+    let captured = matches!(
+        process,
+        engine::Subprocess::Xvfb | engine::Subprocess::Pulse
+    );
+    let encoding = captured && engine.is_encoding();
+    if encoding {
+        let drained = match engine.stop_encode() {
+            Ok(drain) => drain.await.map(|_| ()),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = drained {
+            warn!(
+                "[Manager] couldn't drain engine {} before restart: {}",
+                id, err
+            );
+        }
+    }
+
+    let resources = engine.resources().clone();
+    let (tx, rx) = oneshot::channel();
+    std::thread::spawn(move || {
+        let restarted = engine.restart(process).map(|()| encoding);
+        let _ = tx.send((engine, restarted));
+    });
+    match rx.await {
+        Ok((engine, restarted)) => {
+            let _ = manager.send(ManagerEvent::EngineRestarted(id, engine, restarted));
+        }
+        Err(_) => {
+            error!("[Manager] engine {} panicked while restarting", id);
+            let _ = manager.send(ManagerEvent::EngineStopped(id, resources));
+        }
+    }
+}
+
+/// Stops an engine that has been taken out of the Manager's map, then tells the
+/// Manager its resources are free again. The encoder drains on the Manager's
+/// context, the subprocesses are waited on from a thread of their own so a hung
+/// one doesn't hold up the other engines.
+async fn stop_engine(
+    id: u32,
+    mut engine: engine::Engine,
+    manager: glib::Sender<ManagerEvent>,
+) -> Result<engine::StopReport, failure::Error> {
+    let stopped = engine.drain().await;
+    if let Err(err) = &stopped {
+        warn!("[Manager] couldn't stop engine {}: {}", id, err);
+    }
+
+    let resources = engine.resources().clone();
+    let (tx, rx) = oneshot::channel();
+    std::thread::spawn(move || {
+        drop(engine);
+        let _ = tx.send(());
+    });
+    if rx.await.is_err() {
+        warn!("[Manager] engine {} panicked while tearing down", id);
+    }
+    let _ = manager.send(ManagerEvent::EngineStopped(id, resources));
+
+    stopped
 }
 
 pub struct Manager {}
 
 impl Manager {
     pub fn new() -> glib::Sender<ManagerEvent> {
-        let mut engines = HashMap::new();
+        let mut engines: HashMap<u32, EngineEntry> = HashMap::new();
+        let mut allocator = resources::ResourceAllocator::default();
+        let mut subscribers: HashMap<u32, Vec<mpsc::UnboundedSender<engine::EngineEvent>>> =
+            HashMap::new();
+        let mut webhooks: HashMap<u32, webhook::Webhook> = HashMap::new();
+        let mut scheduled: HashMap<u32, engine::EngineConfig> = HashMap::new();
+        // Why an engine that's being stopped failed, reported once it has ended
+        let mut failures: HashMap<u32, String> = HashMap::new();
+        // Engines that have started but whose Stopped event hasn't been handled yet
+        let mut awaiting: HashSet<u32> = HashSet::new();
+        // Webhooks of ended engines, still delivering what was queued
+        let mut closing_hooks: Vec<JoinHandle<()>> = vec![];
+        let mut shutdown: Option<Shutdown> = None;
 
         let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
 
+        let (events_tx, events_rx) = mpsc::unbounded();
+        let forward = tx.clone();
+        glib::MainContext::ref_thread_default().spawn_local(events_rx.for_each(
+            move |(id, event)| {
+                let _ = forward.send(ManagerEvent::EngineEvent(id, event));
+                future::ready(())
+            },
+        ));
+
+        let manager = tx.clone();
+
+        let supervise = tx.clone();
+        glib::timeout_add_seconds_local(SUPERVISE_INTERVAL_SECS, move || {
+            let _ = supervise.send(ManagerEvent::Supervise);
+            glib::Continue(true)
+        });
+
+        let schedule = tx.clone();
+        glib::timeout_add_seconds_local(SCHEDULE_INTERVAL_SECS, move || {
+            let _ = schedule.send(ManagerEvent::Schedule);
+            glib::Continue(true)
+        });
+
         rx.attach(None, move |msg| {
             match msg {
-                ManagerEvent::EngineSpawn(res, cfg) => {
+                ManagerEvent::EngineSpawn(res, mut cfg) => {
+                    let id = cfg.id;
+                    // A stopped engine's id can be reused, anything else is still live
+                    let rejected = if shutdown.is_some() {
+                        let msg = "the manager is shutting down".to_owned();
+                        Some(ManagerError::Conflict(msg))
+                    } else if is_live(&engines, id) || scheduled.contains_key(&id) {
+                        Some(ManagerError::EngineExists(id))
+                    } else {
+                        None
+                    };
+
+                    let allocated = match (rejected, &cfg.resources) {
+                        (Some(err), _) => Err(err),
+                        (None, Some(_)) => Ok(None),
+                        (None, None) => allocator.allocate().map(Some).map_err(Into::into),
+                    };
+                    let allocated = match allocated {
+                        Ok(allocated) => allocated,
+                        Err(err) => {
+                            warn!("[Manager] couldn't spawn engine {}: {}", id, err);
+                            report_unstarted(&cfg, &err, &mut closing_hooks);
+                            let _ = res.send(Err(err));
+                            return glib::Continue(true);
+                        }
+                    };
+                    if allocated.is_some() {
+                        cfg.resources = allocated.clone();
+                    }
+
+                    if let Some(url) = cfg.webhook_url.clone() {
+                        let hook = webhook::Webhook::new(url, cfg.webhook_secret.clone());
+                        webhooks.insert(id, hook);
+                    }
+
+                    let config = cfg.clone();
+                    engines.insert(
+                        id,
+                        EngineEntry {
+                            config,
+                            slot: Slot::Starting,
+                            deadline: None,
+                        },
+                    );
+                    start_engine(cfg, events_tx.clone(), manager.clone(), res);
+                }
+                ManagerEvent::EngineStarted(id, started, res) => {
+                    let entry = engines.get_mut(&id);
+                    if started.is_ok() {
+                        awaiting.insert(id);
+                    }
+                    match started {
+                        Ok(e) => match entry {
+                            Some(entry) if matches!(entry.slot, Slot::Starting) => {
+                                info!("[Manager] engine {} started", id);
+                                entry.slot = Slot::Running(e);
+                                entry.deadline = entry
+                                    .config
+                                    .max_duration
+                                    .map(|secs| Instant::now() + Duration::from_secs(secs));
+                                // Its encoder already failed while it was starting, or
+                                // everything is being shut down
+                                if failures.contains_key(&id) || shutdown.is_some() {
+                                    entry.stop(&manager);
+                                }
+                                let _ = res.send(Ok(()));
+                            }
+                            // Shut down while it was starting, don't leave it running
+                            _ => {
+                                let stopped = stop_engine(id, e, manager.clone());
+                                glib::MainContext::ref_thread_default().spawn_local(async move {
+                                    let _ = stopped.await;
+                                });
+                                let msg = format!("engine {} was stopped while starting", id);
+                                let _ = res.send(Err(ManagerError::Conflict(msg)));
+                            }
+                        },
+                        Err(err) => {
+                            error!("[Manager] couldn't spawn engine {}: {}", id, err);
+                            if let Some(hook) = webhooks.remove(&id) {
+                                hook.send(webhook::WebhookPayload::failed(id, err.to_string()));
+                                closing_hooks.retain(|hook| !hook.is_finished());
+                                closing_hooks.push(hook.close());
+                            }
+                            if let Some(entry) = engines.remove(&id) {
+                                if let Some(r) = &entry.config.resources {
+                                    allocator.release(r);
+                                }
+                            }
+                            subscribers.remove(&id);
+                            let _ = res.send(Err(err.into()));
+                        }
+                    }
+                }
+                ManagerEvent::EngineSchedule(res, cfg) => {
                     let id = cfg.id;
-                    let eng = engine::Engine::new(cfg).unwrap();
-                    engines.insert(id, eng);
-                    res.send(Ok(())).unwrap();
+                    if is_live(&engines, id) || scheduled.contains_key(&id) {
+                        let _ = res.send(Err(ManagerError::EngineExists(id)));
+                    } else {
+                        info!("[Manager] engine {} scheduled at {:?}", id, cfg.start_at);
+                        scheduled.insert(id, cfg);
+                        let _ = res.send(Ok(()));
+                    }
                 }
-                ManagerEvent::EngineStop(res, key) => match engines.remove(&key) {
+                ManagerEvent::ScheduleList(res) => {
+                    let mut list: Vec<_> = scheduled.values().cloned().collect();
+                    list.sort_by_key(|cfg| (cfg.start_at, cfg.id));
+                    let _ = res.send(list);
+                }
+                ManagerEvent::ScheduleCancel(res, key) => match scheduled.remove(&key) {
                     None => {
-                        res.send(Err(format!("error: no engine found key={}", &key)))
-                            .unwrap();
+                        let _ = res.send(Err(ManagerError::JobNotFound(key)));
+                    }
+                    Some(_) => {
+                        info!("[Manager] canceled scheduled engine {}", key);
+                        let _ = res.send(Ok(()));
                     }
-                    Some(mut e) => {
-                        if let Err(_) = e.stop() {
-                            res.send(Err(String::from("error: couldn't stop engine")))
-                                .unwrap();
-                        } else {
-                            res.send(Ok(())).unwrap();
+                },
+                ManagerEvent::Schedule => {
+                    let due: Vec<u32> = scheduled
+                        .values()
+                        .filter(|cfg| cfg.is_due())
+                        .map(|cfg| cfg.id)
+                        .collect();
+                    for id in due {
+                        if let Some(cfg) = scheduled.remove(&id) {
+                            // Nobody waits on a scheduled start, the spawn logs its
+                            // failures and reports them to the webhook
+                            let (res, _) = oneshot::channel();
+                            let _ = manager.send(ManagerEvent::EngineSpawn(res, cfg));
                         }
                     }
+
+                    let now = Instant::now();
+                    for (id, entry) in engines.iter_mut() {
+                        let idle = match &mut entry.slot {
+                            Slot::Running(e) => e.check_idle(),
+                            _ => continue,
+                        };
+                        if entry.deadline.map_or(false, |deadline| deadline <= now) {
+                            info!("[Manager] engine {} reached its max duration", id);
+                            entry.stop(&manager);
+                        } else if idle && entry.config.idle_action == engine::IdleAction::Stop {
+                            info!("[Manager] engine {} went idle", id);
+                            entry.stop(&manager);
+                        }
+                    }
+                }
+                ManagerEvent::EngineStop(res, key) => match engines.get_mut(&key) {
+                    None => {
+                        let _ = res.send(Err(ManagerError::EngineNotFound(key)));
+                    }
+                    Some(entry) => match std::mem::replace(&mut entry.slot, Slot::Stopping) {
+                        Slot::Running(e) => {
+                            let stopped = stop_engine(key, e, manager.clone());
+                            glib::MainContext::ref_thread_default().spawn_local(async move {
+                                let _ = res.send(stopped.await.map_err(ManagerError::from));
+                            });
+                        }
+                        slot => {
+                            entry.slot = slot;
+                            let msg = format!("engine {} is {:?}", key, entry.state());
+                            let _ = res.send(Err(ManagerError::Conflict(msg)));
+                        }
+                    },
+                },
+                ManagerEvent::EncodeStart(res, key) => match running(&mut engines, key) {
+                    Err(err) => {
+                        let _ = res.send(Err(err));
+                    }
+                    Ok(e) if e.is_encoding() => {
+                        let msg = format!("engine {} is already encoding", key);
+                        let _ = res.send(Err(ManagerError::Conflict(msg)));
+                    }
+                    Ok(e) => {
+                        let _ = res.send(e.start_encode().map_err(ManagerError::from));
+                    }
+                },
+                ManagerEvent::EncodeStop(res, key) => match running(&mut engines, key) {
+                    Err(err) => {
+                        let _ = res.send(Err(err));
+                    }
+                    Ok(e) if !e.is_encoding() => {
+                        let msg = format!("engine {} is not encoding", key);
+                        let _ = res.send(Err(ManagerError::Conflict(msg)));
+                    }
+                    Ok(e) => match e.stop_encode() {
+                        Err(err) => {
+                            let _ = res.send(Err(err.into()));
+                        }
+                        Ok(drain) => {
+                            glib::MainContext::ref_thread_default().spawn_local(async move {
+                                let _ = res.send(drain.await.map_err(ManagerError::from));
+                            });
+                        }
+                    },
+                },
+                ManagerEvent::EnginePause(res, key) => match running(&mut engines, key) {
+                    Err(err) => {
+                        let _ = res.send(Err(err));
+                    }
+                    Ok(e) if !e.is_encoding() => {
+                        let msg = format!("engine {} is not encoding", key);
+                        let _ = res.send(Err(ManagerError::Conflict(msg)));
+                    }
+                    Ok(e) if e.is_paused() => {
+                        let msg = format!("engine {} is already paused", key);
+                        let _ = res.send(Err(ManagerError::Conflict(msg)));
+                    }
+                    Ok(e) => {
+                        let _ = res.send(e.pause().map_err(ManagerError::from));
+                    }
+                },
+                ManagerEvent::EngineResume(res, key) => match running(&mut engines, key) {
+                    Err(err) => {
+                        let _ = res.send(Err(err));
+                    }
+                    Ok(e) if !e.is_paused() => {
+                        let msg = format!("engine {} is not paused", key);
+                        let _ = res.send(Err(ManagerError::Conflict(msg)));
+                    }
+                    Ok(e) => {
+                        let _ = res.send(e.resume().map_err(ManagerError::from));
+                    }
+                },
+                ManagerEvent::EngineHlsDir(res, key) => match running(&mut engines, key) {
+                    Err(err) => {
+                        let _ = res.send(Err(err));
+                    }
+                    Ok(e) => {
+                        let r = e.hls_dir().map(String::from).ok_or_else(|| {
+                            ManagerError::Unavailable(format!("engine {} has no hls output", key))
+                        });
+                        let _ = res.send(r);
+                    }
+                },
+                ManagerEvent::PreviewStart(res, key, offer) => match running(&mut engines, key) {
+                    Err(err) => {
+                        let _ = res.send(Err(err));
+                    }
+                    Ok(e) => match e.start_preview(&offer) {
+                        Err(err) => {
+                            let _ = res.send(Err(err.into()));
+                        }
+                        Ok((session, answer)) => {
+                            // ICE gathering takes a while, don't hold up other engines
+                            let manager = manager.clone();
+                            glib::MainContext::ref_thread_default().spawn_local(async move {
+                                let timeout = glib::timeout_future(PREVIEW_ANSWER_TIMEOUT_MS);
+                                let r = match future::select(answer, timeout).await {
+                                    future::Either::Left((Ok(sdp), _)) => Ok((session, sdp)),
+                                    future::Either::Left((Err(_), _)) => Err(
+                                        ManagerError::Internal("webrtc negotiation failed".into()),
+                                    ),
+                                    future::Either::Right(_) => Err(ManagerError::Internal(
+                                        "webrtc negotiation timed out".into(),
+                                    )),
+                                };
+                                if r.is_err() {
+                                    // Nobody will stop a preview they never got the answer for
+                                    let (tx, _) = oneshot::channel();
+                                    let _ =
+                                        manager.send(ManagerEvent::PreviewStop(tx, key, session));
+                                }
+                                let _ = res.send(r);
+                            });
+                        }
+                    },
+                },
+                ManagerEvent::EngineList(res) => {
+                    let mut list: Vec<_> = engines.values().map(EngineEntry::status).collect();
+                    list.sort_by_key(|status| status.id);
+                    let _ = res.send(list);
+                }
+                ManagerEvent::EngineStatus(res, key) => match engines.get(&key) {
+                    None => {
+                        let _ = res.send(Err(ManagerError::EngineNotFound(key)));
+                    }
+                    Some(entry) => {
+                        let _ = res.send(Ok(entry.status()));
+                    }
+                },
+                ManagerEvent::EngineSubscribe(res, key) => match engines.get(&key) {
+                    None => {
+                        let _ = res.send(Err(ManagerError::EngineNotFound(key)));
+                    }
+                    Some(entry) if matches!(entry.slot, Slot::Stopped) => {
+                        let msg = format!("engine {} has stopped", key);
+                        let _ = res.send(Err(ManagerError::Conflict(msg)));
+                    }
+                    Some(_) => {
+                        let (tx, rx) = mpsc::unbounded();
+                        subscribers.entry(key).or_default().push(tx);
+                        let _ = res.send(Ok(rx));
+                    }
+                },
+                ManagerEvent::EngineStopped(id, resources) => {
+                    info!("[Manager] engine {} stopped", id);
+                    allocator.release(&resources);
+                    if let Some(entry) = engines.get_mut(&id) {
+                        entry.slot = Slot::Stopped;
+                    }
+                    evict_if_ended(&mut engines, &awaiting, id);
+                }
+                ManagerEvent::EngineEvent(id, event) => {
+                    debug!("[Manager] engine {} event {:?}", id, event);
+                    if let engine::EngineEvent::PageNavigated { url } = &event {
+                        if let Some(entry) = engines.get_mut(&id) {
+                            if entry.config.is_complete_url(url) {
+                                info!("[Manager] engine {} page completed at {}", id, url);
+                                entry.stop(&manager);
+                            }
+                        }
+                    }
+                    // A broken pipeline won't record anything more, stop the engine and
+                    // report its recording as failed rather than finished
+                    if let engine::EngineEvent::Error {
+                        message,
+                        fatal: true,
+                        ..
+                    } = &event
+                    {
+                        error!("[Manager] engine {} failed: {}", id, message);
+                        failures.entry(id).or_insert_with(|| message.clone());
+                        if let Some(entry) = engines.get_mut(&id) {
+                            entry.stop(&manager);
+                        }
+                    }
+
+                    let stopped = matches!(event, engine::EngineEvent::Stopped);
+                    let payload = match &event {
+                        engine::EngineEvent::Finished { .. } => {
+                            webhook::WebhookPayload::from_event(id, &event).map(|payload| {
+                                match failures.remove(&id) {
+                                    Some(err) => payload.into_failed(err),
+                                    None => payload,
+                                }
+                            })
+                        }
+                        // Failed without a recording to finish
+                        engine::EngineEvent::Stopped => failures
+                            .remove(&id)
+                            .map(|err| webhook::WebhookPayload::failed(id, err)),
+                        _ => webhook::WebhookPayload::from_event(id, &event),
+                    };
+                    if let (Some(hook), Some(payload)) = (webhooks.get(&id), payload) {
+                        hook.send(payload);
+                    }
+                    if let Some(subs) = subscribers.get_mut(&id) {
+                        subs.retain(|sub| sub.unbounded_send(event.clone()).is_ok());
+                    }
+                    // Dropping the senders ends the subscribers' streams. An engine
+                    // that failed to finish starting also stops, its webhook is
+                    // left for the start error to report to
+                    if stopped && awaiting.remove(&id) {
+                        subscribers.remove(&id);
+                        if let Some(hook) = webhooks.remove(&id) {
+                            closing_hooks.retain(|hook| !hook.is_finished());
+                            closing_hooks.push(hook.close());
+                        }
+                        evict_if_ended(&mut engines, &awaiting, id);
+                    }
+                }
+                ManagerEvent::ShutdownAll(res, deadline) => {
+                    let live = engines
+                        .values()
+                        .filter(|entry| !matches!(entry.slot, Slot::Stopped))
+                        .count();
+                    info!("[Manager] shutting down {} engines", live);
+                    scheduled.clear();
+
+                    // Drain every engine at once rather than one after another. Starting
+                    // and restarting engines are stopped once they're handed back.
+                    for entry in engines.values_mut() {
+                        entry.stop(&manager);
+                    }
+                    shutdown = Some(Shutdown {
+                        res,
+                        deadline,
+                        flushing: false,
+                    });
+
+                    let timer = manager.clone();
+                    glib::timeout_add_local(deadline.as_millis() as u32, move || {
+                        let _ = timer.send(ManagerEvent::ShutdownDeadline);
+                        glib::Continue(false)
+                    });
+                }
+                ManagerEvent::ShutdownFlushed => {
+                    if let Some(shutdown) = shutdown.take() {
+                        info!("[Manager] every engine has stopped");
+                        let _ = shutdown.res.send(());
+                    }
+                }
+                ManagerEvent::ShutdownDeadline => {
+                    if let Some(shutdown) = shutdown.take() {
+                        warn!(
+                            "[Manager] engines didn't stop within {:?}, giving up on them",
+                            shutdown.deadline
+                        );
+                        let _ = shutdown.res.send(());
+                    }
+                }
+                ManagerEvent::Supervise => {
+                    let mut failed = vec![];
+                    let mut restart = vec![];
+                    for (id, entry) in engines.iter_mut() {
+                        if let Slot::Running(e) = &mut entry.slot {
+                            match e.supervise() {
+                                Ok(None) => (),
+                                Ok(Some(process)) => restart.push((*id, process)),
+                                Err(err) => {
+                                    error!("[Manager] engine {} failed: {}", id, err);
+                                    failed.push((*id, err.to_string()));
+                                }
+                            }
+                        }
+                    }
+                    for (id, err) in failed {
+                        failures.entry(id).or_insert(err);
+                        if let Some(entry) = engines.get_mut(&id) {
+                            entry.stop(&manager);
+                        }
+                    }
+                    for (id, process) in restart {
+                        let entry = match engines.get_mut(&id) {
+                            Some(entry) => entry,
+                            None => continue,
+                        };
+                        if let Slot::Running(e) =
+                            std::mem::replace(&mut entry.slot, Slot::Restarting)
+                        {
+                            glib::MainContext::ref_thread_default().spawn_local(restart_engine(
+                                id,
+                                e,
+                                process,
+                                manager.clone(),
+                            ));
+                        }
+                    }
+                }
+                ManagerEvent::EngineRestarted(id, mut e, restarted) => {
+                    let restarted = restarted.and_then(|encode| match encode {
+                        true => e.start_encode(),
+                        false => Ok(()),
+                    });
+                    match engines.get_mut(&id) {
+                        Some(entry) => {
+                            entry.slot = Slot::Running(e);
+                            if let Err(err) = restarted {
+                                error!("[Manager] couldn't restart engine {}: {}", id, err);
+                                failures.entry(id).or_insert_with(|| err.to_string());
+                                entry.stop(&manager);
+                            } else if shutdown.is_some() {
+                                entry.stop(&manager);
+                            }
+                        }
+                        None => {
+                            let stopped = stop_engine(id, e, manager.clone());
+                            glib::MainContext::ref_thread_default().spawn_local(async move {
+                                let _ = stopped.await;
+                            });
+                        }
+                    }
+                }
+                ManagerEvent::EngineDevtools(res, key) => match running(&mut engines, key) {
+                    Err(err) => {
+                        let _ = res.send(Err(err));
+                    }
+                    Ok(e) => {
+                        let _ = res.send(Ok(e.devtools_port()));
+                    }
+                },
+                ManagerEvent::PreviewStop(res, key, session) => match running(&mut engines, key) {
+                    Err(err) => {
+                        let _ = res.send(Err(err));
+                    }
+                    Ok(e) if !e.has_preview(session) => {
+                        let _ = res.send(Err(ManagerError::SessionNotFound(key, session)));
+                    }
+                    Ok(e) => {
+                        let _ = res.send(e.stop_preview(session).map_err(ManagerError::from));
+                    }
                 },
             };
 
+            flush_if_ended(
+                &mut shutdown,
+                &engines,
+                &awaiting,
+                &mut closing_hooks,
+                &manager,
+            );
             glib::Continue(true)
         });
 
         tx
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::RecvTimeoutError;
+
+    #[test]
+    fn start_error_after_stopped_still_sends_failed_webhook() {
+        // Without any binaries on PATH the engine fails to launch dbus-daemon
+        let empty = std::env::temp_dir().join(format!("tapedeck-no-bins-{}", std::process::id()));
+        std::fs::create_dir_all(&empty).unwrap();
+        let (url, requests) = webhook::tests::stand_in(vec![200, 200]);
+
+        let ctx = glib::MainContext::default();
+        let started = engine::tests::with_path(&empty, || {
+            ctx.block_on(async {
+                let manager = Manager::new();
+                let cfg = engine::EngineConfigBuilder::default()
+                    .glib_ctx(glib::MainContext::default())
+                    .id(7)
+                    .webhook_url(Some(url))
+                    .build()
+                    .unwrap();
+
+                let (tx, rx) = oneshot::channel();
+                manager.send(ManagerEvent::EngineSpawn(tx, cfg)).unwrap();
+                // A half-built engine's Stopped is handled before its start error
+                let stopped = ManagerEvent::EngineEvent(7, engine::EngineEvent::Stopped);
+                manager.send(stopped).unwrap();
+                rx.await.unwrap()
+            })
+        });
+        assert!(started.is_err());
+
+        let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body["event"], "recording.failed");
+        assert_eq!(body["engine_id"], 7);
+        // and only once
+        assert_eq!(
+            requests.recv_timeout(Duration::from_secs(1)).err(),
+            Some(RecvTimeoutError::Timeout)
+        );
+
+        std::fs::remove_dir_all(&empty).unwrap();
+    }
+}
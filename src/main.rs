@@ -1,53 +1,213 @@
 use enclose::enc;
 use futures::channel::oneshot;
+use futures::prelude::*;
+use rocket::fs::NamedFile;
+use rocket::http::{ContentType, Status};
+use rocket::response::status::Created;
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::{self, Json};
 use rocket::State;
-use std::collections::HashMap;
 use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tapedeck::engine;
+use tapedeck::error::{ApiError, ManagerError};
+use tapedeck::*;
 use tokio::runtime::Runtime;
 
-mod engine;
+#[macro_use]
+extern crate rocket;
 
 #[get("/")]
 fn index() -> &'static str {
     "hello"
 }
 
-#[post("/start/<id>")]
+/// Sends an event to the Manager and waits for its reply.
+async fn ask<T>(
+    mgr: &glib::Sender<ManagerEvent>,
+    event: impl FnOnce(oneshot::Sender<Result<T, ManagerError>>) -> ManagerEvent,
+) -> Result<T, ApiError> {
+    let (tx, rx) = oneshot::channel();
+    mgr.send(event(tx))
+        .map_err(|_| ApiError::internal("manager is not running".to_owned()))?;
+    Ok(rx.await??)
+}
+
+/// Starts an engine from the `EngineConfig` in the body, or schedules it if its
+/// `start_at` is still to come. The id is taken from the path.
+#[post("/start/<id>", data = "<cfg>")]
 async fn start(
     ctx: &State<glib::MainContext>,
     mgr: &State<glib::Sender<ManagerEvent>>,
     id: u32,
-) -> String {
-    let cfg = engine::EngineConfigBuilder::default()
-        .glib_ctx((*ctx).clone())
-        .id(id)
-        .size((1920, 1080))
-        .url("https://www.youtube.com/watch?v=JIx_ILapASY".to_owned())
-        .gst_debug(false)
-        .encode_dir(Some("/tmp".to_string()))
-        .build()
-        .unwrap();
-
-    let (tx, rx) = oneshot::channel();
-
-    mgr.send(ManagerEvent::EngineSpawn(tx, cfg)).unwrap();
+    cfg: Result<Json<engine::EngineConfig>, json::Error<'_>>,
+) -> Result<&'static str, ApiError> {
+    let mut cfg = match cfg {
+        Ok(cfg) => cfg.into_inner(),
+        Err(json::Error::Parse(_, e)) => {
+            let error = engine::ConfigError::new("body", e.to_string());
+            return Err(ApiError::invalid_config(vec![error]));
+        }
+        Err(json::Error::Io(e)) => return Err(ApiError::bad_request(e.to_string())),
+    };
+    cfg.id = id;
+    cfg.glib_ctx = (*ctx).clone();
+    cfg.validate().map_err(ApiError::invalid_config)?;
 
-    if let Err(err) = rx.await.unwrap() {
-        return err;
+    if !cfg.is_due() {
+        ask(mgr, |tx| ManagerEvent::EngineSchedule(tx, cfg)).await?;
+        return Ok("scheduled");
     }
+    ask(mgr, |tx| ManagerEvent::EngineSpawn(tx, cfg)).await?;
+    Ok("started")
+}
 
-    "started".to_owned()
+/// Engines waiting for their `start_at`, soonest first
+#[get("/schedule")]
+async fn schedule(
+    mgr: &State<glib::Sender<ManagerEvent>>,
+) -> Result<Json<Vec<engine::EngineConfig>>, ApiError> {
+    let (tx, rx) = oneshot::channel();
+    mgr.send(ManagerEvent::ScheduleList(tx))
+        .map_err(|_| ApiError::internal("manager is not running".to_owned()))?;
+    Ok(Json(rx.await?))
+}
+
+#[delete("/schedule/<id>")]
+async fn cancel_schedule(
+    mgr: &State<glib::Sender<ManagerEvent>>,
+    id: u32,
+) -> Result<&'static str, ApiError> {
+    ask(mgr, |tx| ManagerEvent::ScheduleCancel(tx, id)).await?;
+    Ok("canceled")
 }
 
 #[get("/stop/<id>")]
-async fn stop(mgr: &State<glib::Sender<ManagerEvent>>, id: u32) -> String {
+async fn stop(
+    mgr: &State<glib::Sender<ManagerEvent>>,
+    id: u32,
+) -> Result<Json<engine::StopReport>, ApiError> {
+    let report = ask(mgr, |tx| ManagerEvent::EngineStop(tx, id)).await?;
+    Ok(Json(report))
+}
+
+#[get("/engines")]
+async fn engines(
+    mgr: &State<glib::Sender<ManagerEvent>>,
+) -> Result<Json<Vec<engine::EngineStatus>>, ApiError> {
     let (tx, rx) = oneshot::channel();
-    mgr.send(ManagerEvent::EngineStop(tx, id)).unwrap();
-    if let Err(err) = rx.await.unwrap() {
-        return err;
-    }
+    mgr.send(ManagerEvent::EngineList(tx))
+        .map_err(|_| ApiError::internal("manager is not running".to_owned()))?;
+    Ok(Json(rx.await?))
+}
 
-    "stopped".to_owned()
+#[get("/engines/<id>")]
+async fn engine_status(
+    mgr: &State<glib::Sender<ManagerEvent>>,
+    id: u32,
+) -> Result<Json<engine::EngineStatus>, ApiError> {
+    let status = ask(mgr, |tx| ManagerEvent::EngineStatus(tx, id)).await?;
+    Ok(Json(status))
+}
+
+/// Chromium remote debugging port of an engine. Chromium listens on every
+/// interface, so it's reached on the same host as this server.
+#[get("/engines/<id>/devtools")]
+async fn devtools(mgr: &State<glib::Sender<ManagerEvent>>, id: u32) -> Result<String, ApiError> {
+    let port = ask(mgr, |tx| ManagerEvent::EngineDevtools(tx, id)).await?;
+    Ok(port.to_string())
+}
+
+/// Server-sent events for everything that happens to an engine, the stream ends
+/// once the engine has stopped.
+#[get("/engines/<id>/events")]
+async fn events(
+    mgr: &State<glib::Sender<ManagerEvent>>,
+    id: u32,
+) -> Result<EventStream<impl Stream<Item = Event>>, ApiError> {
+    let events = ask(mgr, |tx| ManagerEvent::EngineSubscribe(tx, id)).await?;
+    Ok(EventStream::from(events.map(|event| Event::json(&event))))
+}
+
+#[post("/encode/start/<id>")]
+async fn start_encode(
+    mgr: &State<glib::Sender<ManagerEvent>>,
+    id: u32,
+) -> Result<&'static str, ApiError> {
+    ask(mgr, |tx| ManagerEvent::EncodeStart(tx, id)).await?;
+    Ok("encoding")
+}
+
+#[post("/encode/stop/<id>")]
+async fn stop_encode(
+    mgr: &State<glib::Sender<ManagerEvent>>,
+    id: u32,
+) -> Result<Json<engine::StopReport>, ApiError> {
+    let report = ask(mgr, |tx| ManagerEvent::EncodeStop(tx, id)).await?;
+    Ok(Json(report))
+}
+
+#[post("/pause/<id>")]
+async fn pause(mgr: &State<glib::Sender<ManagerEvent>>, id: u32) -> Result<&'static str, ApiError> {
+    ask(mgr, |tx| ManagerEvent::EnginePause(tx, id)).await?;
+    Ok("paused")
+}
+
+#[post("/resume/<id>")]
+async fn resume(
+    mgr: &State<glib::Sender<ManagerEvent>>,
+    id: u32,
+) -> Result<&'static str, ApiError> {
+    ask(mgr, |tx| ManagerEvent::EngineResume(tx, id)).await?;
+    Ok("resumed")
+}
+
+#[get("/engines/<id>/hls/<file..>")]
+async fn hls(
+    mgr: &State<glib::Sender<ManagerEvent>>,
+    id: u32,
+    file: PathBuf,
+) -> Result<(ContentType, NamedFile), ApiError> {
+    let dir = ask(mgr, |tx| ManagerEvent::EngineHlsDir(tx, id)).await?;
+
+    let content_type = match file.extension().and_then(|ext| ext.to_str()) {
+        Some("m3u8") => ContentType::new("application", "vnd.apple.mpegurl"),
+        Some("ts") => ContentType::new("video", "mp2t"),
+        _ => ContentType::Binary,
+    };
+    let named = NamedFile::open(Path::new(&dir).join(&file))
+        .await
+        .map_err(|_| {
+            let msg = format!("no hls file {}", file.display());
+            ApiError::new(Status::NotFound, "not_found", msg)
+        })?;
+
+    Ok((content_type, named))
+}
+
+/// WHEP style preview: the body is the viewer's SDP offer and the response is the
+/// SDP answer, with the session to DELETE in the Location header.
+#[post("/engines/<id>/whep", data = "<offer>")]
+async fn whep_start(
+    mgr: &State<glib::Sender<ManagerEvent>>,
+    id: u32,
+    offer: String,
+) -> Result<Created<(ContentType, String)>, ApiError> {
+    let (session, answer) = ask(mgr, |tx| ManagerEvent::PreviewStart(tx, id, offer)).await?;
+
+    Ok(Created::new(format!("/engines/{}/whep/{}", id, session))
+        .body((ContentType::new("application", "sdp"), answer)))
+}
+
+#[delete("/engines/<id>/whep/<session>")]
+async fn whep_stop(
+    mgr: &State<glib::Sender<ManagerEvent>>,
+    id: u32,
+    session: u32,
+) -> Result<&'static str, ApiError> {
+    ask(mgr, |tx| ManagerEvent::PreviewStop(tx, id, session)).await?;
+    Ok("preview stopped")
 }
 
 fn web_init(ctx: glib::MainContext, sender: glib::Sender<ManagerEvent>) {
@@ -57,7 +217,27 @@ fn web_init(ctx: glib::MainContext, sender: glib::Sender<ManagerEvent>) {
             rocket::build()
                 .manage(ctx)
                 .manage(sender)
-                .mount("/", routes![index, start, stop])
+                .mount(
+                    "/",
+                    routes![
+                        index,
+                        start,
+                        schedule,
+                        cancel_schedule,
+                        stop,
+                        engines,
+                        engine_status,
+                        events,
+                        devtools,
+                        start_encode,
+                        stop_encode,
+                        pause,
+                        resume,
+                        hls,
+                        whep_start,
+                        whep_stop
+                    ],
+                )
                 .launch()
                 .await
                 .expect("error in web server");
@@ -65,6 +245,15 @@ fn web_init(ctx: glib::MainContext, sender: glib::Sender<ManagerEvent>) {
     });
 }
 
+/// How long engines get to finalize their recordings on SIGINT/SIGTERM, from
+/// `SHUTDOWN_TIMEOUT` in seconds
+fn shutdown_timeout() -> Duration {
+    std::env::var("SHUTDOWN_TIMEOUT")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map_or(Duration::from_secs(30), Duration::from_secs)
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let ctx = glib::MainContext::default();
     ctx.push_thread_default();
@@ -74,9 +263,11 @@ fn main() -> Result<(), Box<dyn Error>> {
     gst::init()?;
 
     let manager = Manager::new();
-    web_init(ctx.clone(), manager);
+    web_init(ctx.clone(), manager.clone());
 
-    ctrlc::set_handler(enc!( (main_loop) move || {
+    let timeout = shutdown_timeout();
+    ctrlc::set_handler(enc!( (main_loop, manager) move || {
+        shutdown_all(&manager, timeout);
         main_loop.quit();
     }))?;
 
@@ -0,0 +1,145 @@
+use failure::{format_err, Error};
+use futures::channel::oneshot;
+use gst::prelude::*;
+use std::sync::{Arc, Mutex};
+
+/// Launches a low latency webrtc view of the engine's display and audio.
+///
+/// `offer` is the viewer's SDP offer (WHEP style, no trickle ICE). The returned
+/// receiver resolves with the SDP answer once ICE gathering has completed, or is
+/// cancelled if negotiation fails.
+pub fn launch_gstreamer_preview(
+    display: &str,
+    pulse_server: &str,
+    offer: &str,
+) -> Result<(gst::Pipeline, oneshot::Receiver<String>), Error> {
+    let offer = gst_sdp::SDPMessage::parse_buffer(offer.as_bytes())
+        .map_err(|_| format_err!("couldn't parse sdp offer"))?;
+    let offer = gst_webrtc::WebRTCSessionDescription::new(gst_webrtc::WebRTCSDPType::Offer, offer);
+
+    let pipeline = gst::Pipeline::new(None);
+
+    let ximagesrc = gst::ElementFactory::make("ximagesrc", None)?;
+    ximagesrc.set_property_from_str("display-name", &display);
+    ximagesrc.set_property_from_str("show-pointer", "false");
+    ximagesrc.set_property_from_str("use-damage", "false");
+
+    let caps = gst::Caps::builder("video/x-raw")
+        .field("framerate", gst::Fraction::new(30, 1))
+        .build();
+    let caps_filter = gst::ElementFactory::make("capsfilter", None)?;
+    caps_filter.set_property("caps", &caps)?;
+
+    let video_queue = gst::ElementFactory::make("queue", None)?;
+    video_queue.set_property_from_str("leaky", "downstream");
+    let video_convert = gst::ElementFactory::make("videoconvert", None)?;
+    let video_enc = gst::ElementFactory::make("vp8enc", None)?;
+    video_enc.set_property_from_str("deadline", "1");
+    video_enc.set_property_from_str("keyframe-max-dist", "60");
+    let video_pay = gst::ElementFactory::make("rtpvp8pay", None)?;
+
+    let pulsesrc = gst::ElementFactory::make("pulsesrc", None)?;
+    pulsesrc.set_property_from_str("server", &pulse_server);
+
+    let audio_queue = gst::ElementFactory::make("queue", None)?;
+    audio_queue.set_property_from_str("leaky", "downstream");
+    let audio_convert = gst::ElementFactory::make("audioconvert", None)?;
+    let audio_resample = gst::ElementFactory::make("audioresample", None)?;
+    let audio_enc = gst::ElementFactory::make("opusenc", None)?;
+    let audio_pay = gst::ElementFactory::make("rtpopuspay", None)?;
+
+    let webrtcbin = gst::ElementFactory::make("webrtcbin", None)?;
+    webrtcbin.set_property_from_str("bundle-policy", "max-bundle");
+    webrtcbin.set_property_from_str("stun-server", "stun://stun.l.google.com:19302");
+
+    pipeline.add_many(&[
+        &ximagesrc,
+        &caps_filter,
+        &video_queue,
+        &video_convert,
+        &video_enc,
+        &video_pay,
+        &pulsesrc,
+        &audio_queue,
+        &audio_convert,
+        &audio_resample,
+        &audio_enc,
+        &audio_pay,
+        &webrtcbin,
+    ])?;
+
+    gst::Element::link_many(&[
+        &ximagesrc,
+        &caps_filter,
+        &video_queue,
+        &video_convert,
+        &video_enc,
+        &video_pay,
+    ])?;
+    gst::Element::link_many(&[
+        &pulsesrc,
+        &audio_queue,
+        &audio_convert,
+        &audio_resample,
+        &audio_enc,
+        &audio_pay,
+    ])?;
+    video_pay.link_pads(None, &webrtcbin, Some("sink_%u"))?;
+    audio_pay.link_pads(None, &webrtcbin, Some("sink_%u"))?;
+
+    // The answer is only handed out once every candidate is in it
+    let (answer_tx, answer_rx) = oneshot::channel();
+    let answer_tx = Arc::new(Mutex::new(Some(answer_tx)));
+    let gathered_tx = answer_tx.clone();
+    webrtcbin.connect_notify(Some("ice-gathering-state"), move |webrtcbin, _| {
+        let state = webrtcbin
+            .property("ice-gathering-state")
+            .ok()
+            .and_then(|v| v.get::<gst_webrtc::WebRTCICEGatheringState>().ok());
+        if state != Some(gst_webrtc::WebRTCICEGatheringState::Complete) {
+            return;
+        }
+
+        let sdp = webrtcbin
+            .property("local-description")
+            .ok()
+            .and_then(|v| v.get::<gst_webrtc::WebRTCSessionDescription>().ok())
+            .and_then(|desc| desc.sdp().as_text().ok());
+        match (sdp, gathered_tx.lock().unwrap().take()) {
+            (Some(sdp), Some(tx)) => {
+                let _ = tx.send(sdp);
+            }
+            _ => warn!("webrtc preview has no local description after ice gathering"),
+        }
+    });
+
+    pipeline.set_state(gst::State::Playing)?;
+
+    webrtcbin.emit_by_name("set-remote-description", &[&offer, &None::<gst::Promise>])?;
+
+    let promise =
+        gst::Promise::with_change_func(gst::glib::clone!(@weak webrtcbin => move |reply| {
+            let answer = match reply {
+                Ok(Some(reply)) => reply
+                    .get::<gst_webrtc::WebRTCSessionDescription>("answer")
+                    .ok(),
+                _ => None,
+            };
+            match answer {
+                Some(answer) => {
+                    let _ = webrtcbin.emit_by_name(
+                        "set-local-description",
+                        &[&answer, &None::<gst::Promise>],
+                    );
+                }
+                None => {
+                    // Dropping the sender cancels the waiting viewer
+                    warn!("webrtc preview couldn't create an answer");
+                    answer_tx.lock().unwrap().take();
+                }
+            }
+        }));
+    webrtcbin.emit_by_name("create-answer", &[&None::<gst::Structure>, &promise])?;
+
+    Ok((pipeline, answer_rx))
+}
@@ -0,0 +1,352 @@
+use failure::{format_err, Error};
+use gst::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoCodec {
+    X264,
+    X265,
+    Vp8,
+    Vp9,
+    Av1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateControl {
+    Cbr,
+    Vbr,
+    Crf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCodec {
+    Opus,
+    Aac,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    Mp4,
+    Matroska,
+    Webm,
+}
+
+impl Default for Container {
+    fn default() -> Self {
+        Container::Mp4
+    }
+}
+
+impl Container {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Matroska => "mkv",
+            Container::Webm => "webm",
+        }
+    }
+
+    pub fn make_muxer(&self) -> Result<gst::Element, Error> {
+        let name = match self {
+            Container::Mp4 => "mp4mux",
+            Container::Matroska => "matroskamux",
+            Container::Webm => "webmmux",
+        };
+        Ok(gst::ElementFactory::make(name, None)?)
+    }
+
+    /// Checks the container can carry the codecs `profile` encodes to.
+    pub fn check(&self, profile: &EncodeProfile) -> Result<(), Error> {
+        let supported = match self {
+            Container::Mp4 => !matches!(profile.codec, VideoCodec::Vp8),
+            Container::Matroska => true,
+            Container::Webm => {
+                !matches!(profile.codec, VideoCodec::X264 | VideoCodec::X265)
+                    && profile.audio_codec == AudioCodec::Opus
+            }
+        };
+        match supported {
+            true => Ok(()),
+            false => Err(format_err!(
+                "{:?} can't carry {:?} video with {:?} audio",
+                self,
+                profile.codec,
+                profile.audio_codec
+            )),
+        }
+    }
+}
+
+/// Encoder settings shared by recording and transcoding.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncodeProfile {
+    pub codec: VideoCodec,
+
+    /// Encoder speed/quality preset. x264/x265 take their speed-preset names, vp8/vp9
+    /// take `realtime`, `good` or `best` and av1 takes the encoder's numeric preset.
+    pub preset: String,
+
+    pub rate_control: RateControl,
+
+    /// Target video bitrate in kbit/s, ignored for CRF
+    pub bitrate: u32,
+
+    /// Constant quality level used for CRF, in the codec's own scale
+    pub quality: u32,
+
+    /// Maximum frames between keyframes, the encoder picks when unset
+    pub keyframe_interval: Option<u32>,
+
+    pub framerate: u32,
+
+    pub audio_codec: AudioCodec,
+
+    /// Audio bitrate in bit/s
+    pub audio_bitrate: u32,
+}
+
+impl Default for EncodeProfile {
+    fn default() -> Self {
+        EncodeProfile {
+            codec: VideoCodec::X264,
+            preset: "ultrafast".to_owned(),
+            rate_control: RateControl::Cbr,
+            bitrate: 8192,
+            quality: 23,
+            keyframe_interval: None,
+            framerate: 30,
+            audio_codec: AudioCodec::Opus,
+            audio_bitrate: 128000,
+        }
+    }
+}
+
+impl EncodeProfile {
+    /// Builds the configured video encoder.
+    pub fn make_video_encoder(&self) -> Result<gst::Element, Error> {
+        let enc = match self.codec {
+            VideoCodec::X264 => {
+                let enc = gst::ElementFactory::make("x264enc", None)?;
+                enc.set_property_from_str("speed-preset", &self.preset);
+                match self.rate_control {
+                    // x264enc's "cbr" pass is single-pass ABR, the VBV buffer is what
+                    // caps the rate; without one the bitrate is only an average
+                    RateControl::Cbr => {
+                        enc.set_property_from_str("pass", "cbr");
+                        enc.set_property_from_str("bitrate", &self.bitrate.to_string());
+                        enc.set_property_from_str("vbv-buf-capacity", "1000");
+                    }
+                    RateControl::Vbr => {
+                        enc.set_property_from_str("pass", "cbr");
+                        enc.set_property_from_str("bitrate", &self.bitrate.to_string());
+                        enc.set_property_from_str("vbv-buf-capacity", "0");
+                    }
+                    RateControl::Crf => {
+                        enc.set_property_from_str("pass", "qual");
+                        enc.set_property_from_str("quantizer", &self.quality.to_string());
+                    }
+                }
+                if let Some(n) = self.keyframe_interval {
+                    enc.set_property_from_str("key-int-max", &n.to_string());
+                }
+                enc
+            }
+            VideoCodec::X265 => {
+                let enc = gst::ElementFactory::make("x265enc", None)?;
+                enc.set_property_from_str("speed-preset", &self.preset);
+                let options = match self.rate_control {
+                    RateControl::Cbr => {
+                        enc.set_property_from_str("bitrate", &self.bitrate.to_string());
+                        format!("vbv-maxrate={0}:vbv-bufsize={0}", self.bitrate)
+                    }
+                    RateControl::Vbr => {
+                        enc.set_property_from_str("bitrate", &self.bitrate.to_string());
+                        String::new()
+                    }
+                    RateControl::Crf => format!("crf={}", self.quality),
+                };
+                enc.set_property_from_str("option-string", &options);
+                if let Some(n) = self.keyframe_interval {
+                    enc.set_property_from_str("key-int-max", &n.to_string());
+                }
+                enc
+            }
+            VideoCodec::Vp8 | VideoCodec::Vp9 => {
+                let enc = match self.codec {
+                    VideoCodec::Vp8 => gst::ElementFactory::make("vp8enc", None)?,
+                    _ => gst::ElementFactory::make("vp9enc", None)?,
+                };
+                let deadline = match self.preset.as_str() {
+                    "realtime" => "1",
+                    "best" => "0",
+                    _ => "1000000",
+                };
+                enc.set_property_from_str("deadline", deadline);
+                enc.set_property_from_str("cpu-used", "8");
+                match self.rate_control {
+                    RateControl::Cbr | RateControl::Vbr => {
+                        let end_usage = match self.rate_control {
+                            RateControl::Cbr => "cbr",
+                            _ => "vbr",
+                        };
+                        enc.set_property_from_str("end-usage", end_usage);
+                        enc.set_property_from_str(
+                            "target-bitrate",
+                            &(self.bitrate * 1000).to_string(),
+                        );
+                    }
+                    RateControl::Crf => {
+                        enc.set_property_from_str("end-usage", "cq");
+                        enc.set_property_from_str("cq-level", &self.quality.to_string());
+                    }
+                }
+                if let Some(n) = self.keyframe_interval {
+                    enc.set_property_from_str("keyframe-max-dist", &n.to_string());
+                }
+                enc
+            }
+            VideoCodec::Av1 => {
+                // Prefer SVT-AV1, fall back to rav1e from gst-plugins-rs
+                match gst::ElementFactory::make("svtav1enc", None) {
+                    Ok(enc) => {
+                        enc.set_property_from_str("preset", &self.preset);
+                        match self.rate_control {
+                            RateControl::Crf => {
+                                enc.set_property_from_str("crf", &self.quality.to_string());
+                            }
+                            _ => {
+                                enc.set_property_from_str(
+                                    "target-bitrate",
+                                    &self.bitrate.to_string(),
+                                );
+                            }
+                        }
+                        if let Some(n) = self.keyframe_interval {
+                            enc.set_property_from_str("intra-period-length", &n.to_string());
+                        }
+                        enc
+                    }
+                    Err(_) => {
+                        let enc = gst::ElementFactory::make("rav1enc", None)
+                            .map_err(|_| format_err!("no av1 encoder available"))?;
+                        if let Ok(speed) = self.preset.parse::<u32>() {
+                            enc.set_property_from_str("speed-preset", &speed.to_string());
+                        }
+                        match self.rate_control {
+                            RateControl::Crf => {
+                                enc.set_property_from_str("quantizer", &self.quality.to_string());
+                            }
+                            _ => {
+                                enc.set_property_from_str(
+                                    "bitrate",
+                                    &(self.bitrate * 1000).to_string(),
+                                );
+                            }
+                        }
+                        if let Some(n) = self.keyframe_interval {
+                            enc.set_property_from_str("max-key-frame-interval", &n.to_string());
+                        }
+                        enc
+                    }
+                }
+            }
+        };
+
+        Ok(enc)
+    }
+
+    /// Builds the parser that should sit between the video encoder and a muxer, if
+    /// the codec needs one.
+    pub fn make_video_parser(&self) -> Result<Option<gst::Element>, Error> {
+        let name = match self.codec {
+            VideoCodec::X264 => "h264parse",
+            VideoCodec::X265 => "h265parse",
+            VideoCodec::Av1 => "av1parse",
+            VideoCodec::Vp8 | VideoCodec::Vp9 => return Ok(None),
+        };
+        Ok(Some(gst::ElementFactory::make(name, None)?))
+    }
+
+    /// Builds the configured audio encoder, followed by a parser when the codec needs
+    /// one before muxing. Raw audio should be converted and resampled before it.
+    pub fn make_audio_encoder(&self) -> Result<(gst::Element, Option<gst::Element>), Error> {
+        let (enc, parse) = match self.audio_codec {
+            AudioCodec::Opus => (gst::ElementFactory::make("opusenc", None)?, None),
+            AudioCodec::Aac => (
+                make_aac_encoder()?,
+                Some(gst::ElementFactory::make("aacparse", None)?),
+            ),
+        };
+        enc.set_property_from_str("bitrate", &self.audio_bitrate.to_string());
+
+        Ok((enc, parse))
+    }
+}
+
+/// Makes the first AAC encoder this gstreamer install has, best quality first.
+pub fn make_aac_encoder() -> Result<gst::Element, Error> {
+    let factories = ["fdkaacenc", "voaacenc", "avenc_aac"];
+    factories
+        .iter()
+        .find_map(|name| gst::ElementFactory::make(name, None).ok())
+        .ok_or(format_err!("no element available from {:?}", factories))
+}
+
+impl FromStr for VideoCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x264" | "h264" => Ok(VideoCodec::X264),
+            "x265" | "h265" => Ok(VideoCodec::X265),
+            "vp8" => Ok(VideoCodec::Vp8),
+            "vp9" => Ok(VideoCodec::Vp9),
+            "av1" => Ok(VideoCodec::Av1),
+            _ => Err(format!("unknown video codec {}", s)),
+        }
+    }
+}
+
+impl FromStr for RateControl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cbr" => Ok(RateControl::Cbr),
+            "vbr" => Ok(RateControl::Vbr),
+            "crf" => Ok(RateControl::Crf),
+            _ => Err(format!("unknown rate control {}", s)),
+        }
+    }
+}
+
+impl FromStr for Container {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mp4" => Ok(Container::Mp4),
+            "mkv" | "matroska" => Ok(Container::Matroska),
+            "webm" => Ok(Container::Webm),
+            _ => Err(format!("unknown container {}", s)),
+        }
+    }
+}
+
+impl FromStr for AudioCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "opus" => Ok(AudioCodec::Opus),
+            "aac" => Ok(AudioCodec::Aac),
+            _ => Err(format!("unknown audio codec {}", s)),
+        }
+    }
+}
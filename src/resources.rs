@@ -0,0 +1,116 @@
+use failure::{format_err, Error};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::net::TcpListener;
+use std::ops::Range;
+use std::path::Path;
+
+/// Host resources an engine runs on.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Resources {
+    /// X display number handed to Xvfb
+    pub display: u32,
+
+    /// TCP port of the engine's pulseaudio server
+    pub pulse_port: u16,
+
+    /// Chromium remote debugging port
+    pub devtools_port: u16,
+}
+
+impl Resources {
+    pub fn display_name(&self) -> String {
+        format!(":{}", self.display)
+    }
+
+    pub fn pulse_server(&self) -> String {
+        format!("tcp:localhost:{}", self.pulse_port)
+    }
+}
+
+/// Hands out X displays and TCP ports that are free on this host and not already
+/// held by another engine.
+pub struct ResourceAllocator {
+    displays: Range<u32>,
+    ports: Range<u16>,
+    held_displays: HashSet<u32>,
+    held_ports: HashSet<u16>,
+}
+
+impl Default for ResourceAllocator {
+    fn default() -> Self {
+        ResourceAllocator::new(100..1000, 20000..30000)
+    }
+}
+
+impl ResourceAllocator {
+    pub fn new(displays: Range<u32>, ports: Range<u16>) -> Self {
+        ResourceAllocator {
+            displays,
+            ports,
+            held_displays: HashSet::new(),
+            held_ports: HashSet::new(),
+        }
+    }
+
+    pub fn allocate(&mut self) -> Result<Resources, Error> {
+        let display = self.allocate_display()?;
+        let pulse_port = match self.allocate_port() {
+            Ok(port) => port,
+            Err(e) => {
+                self.held_displays.remove(&display);
+                return Err(e);
+            }
+        };
+        let devtools_port = match self.allocate_port() {
+            Ok(port) => port,
+            Err(e) => {
+                self.held_displays.remove(&display);
+                self.held_ports.remove(&pulse_port);
+                return Err(e);
+            }
+        };
+
+        Ok(Resources {
+            display,
+            pulse_port,
+            devtools_port,
+        })
+    }
+
+    pub fn release(&mut self, resources: &Resources) {
+        self.held_displays.remove(&resources.display);
+        self.held_ports.remove(&resources.pulse_port);
+        self.held_ports.remove(&resources.devtools_port);
+    }
+
+    /// Picks a display nobody holds and no X server has locked. Lock files left
+    /// behind by a dead Xvfb are skipped too, since Xvfb refuses to start on them.
+    fn allocate_display(&mut self) -> Result<u32, Error> {
+        let held = &self.held_displays;
+        let display = self
+            .displays
+            .clone()
+            .find(|n| {
+                !held.contains(n)
+                    && !Path::new(&format!("/tmp/.X{}-lock", n)).exists()
+                    && !Path::new(&format!("/tmp/.X11-unix/X{}", n)).exists()
+            })
+            .ok_or(format_err!("no free X display in {:?}", self.displays))?;
+
+        self.held_displays.insert(display);
+        Ok(display)
+    }
+
+    fn allocate_port(&mut self) -> Result<u16, Error> {
+        let held = &self.held_ports;
+        let port = self
+            .ports
+            .clone()
+            .find(|port| !held.contains(port) && TcpListener::bind(("0.0.0.0", *port)).is_ok())
+            .ok_or(format_err!("no free tcp port in {:?}", self.ports))?;
+
+        self.held_ports.insert(port);
+        Ok(port)
+    }
+}
@@ -0,0 +1,248 @@
+use failure::{format_err, Error};
+use gst::prelude::*;
+use std::path::Path;
+
+use crate::profile::{Container, EncodeProfile};
+
+#[derive(Builder, Debug, PartialEq)]
+pub struct TranscodeConfig {
+    /// Input file path or uri
+    pub input: String,
+
+    pub output: String,
+
+    #[builder(default)]
+    pub profile: EncodeProfile,
+
+    /// Container for the output, derived from the output extension when unset
+    #[builder(default = "None")]
+    pub container: Option<Container>,
+
+    /// Scale factor applied to the input resolution
+    #[builder(default = "None")]
+    pub scale: Option<f64>,
+
+    /// Trim everything before this many seconds
+    #[builder(default = "None")]
+    pub start: Option<f64>,
+
+    /// Trim everything after this many seconds
+    #[builder(default = "None")]
+    pub end: Option<f64>,
+}
+
+/// Decodes `cfg.input` and re-encodes it to `cfg.output`, blocking until the output
+/// is finalized. Progress is reported on stdout.
+pub fn transcode(cfg: &TranscodeConfig) -> Result<(), Error> {
+    let container = match cfg.container {
+        Some(container) => container,
+        None => Path::new(&cfg.output)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .ok_or(format_err!("can't pick a container for {}", cfg.output))?
+            .parse()
+            .map_err(|e: String| format_err!("{}", e))?,
+    };
+    container.check(&cfg.profile)?;
+
+    let uri = match cfg.input.contains("://") {
+        true => cfg.input.clone(),
+        false => {
+            let path = std::fs::canonicalize(&cfg.input)?;
+            url::Url::from_file_path(&path)
+                .map_err(|_| format_err!("invalid input path {}", cfg.input))?
+                .to_string()
+        }
+    };
+
+    let pipeline = gst::Pipeline::new(None);
+
+    let decode = gst::ElementFactory::make("uridecodebin", None)?;
+    decode.set_property_from_str("uri", &uri);
+
+    let mux = container.make_muxer()?;
+    let filesink = gst::ElementFactory::make("filesink", None)?;
+    filesink.set_property_from_str("location", &cfg.output);
+
+    pipeline.add_many(&[&decode, &mux, &filesink])?;
+    gst::Element::link_many(&[&mux, &filesink])?;
+
+    let profile = cfg.profile.clone();
+    let scale = cfg.scale;
+    let pipeline_weak = pipeline.downgrade();
+    decode.connect_pad_added(move |_, pad| {
+        let pipeline = match pipeline_weak.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        if let Err(e) = link_decoded_pad(&pipeline, pad, &mux, &profile, scale) {
+            error!("couldn't transcode {}: {}", pad.name(), e);
+        }
+    });
+
+    let bus = pipeline.bus().unwrap();
+
+    // Preroll first so the trim seek lands before anything gets encoded
+    pipeline.set_state(gst::State::Paused)?;
+    let prerolled = bus.timed_pop_filtered(
+        gst::ClockTime::NONE,
+        &[gst::MessageType::AsyncDone, gst::MessageType::Error],
+    );
+    if let Some(msg) = prerolled {
+        if let gst::MessageView::Error(err) = msg.view() {
+            pipeline.set_state(gst::State::Null)?;
+            return Err(format_err!("couldn't open {}: {}", cfg.input, err.error()));
+        }
+    }
+
+    let start = cfg.start.map(seconds);
+    let end = cfg.end.map(seconds);
+    if start.is_some() || end.is_some() {
+        pipeline.seek(
+            1.0,
+            gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE,
+            gst::SeekType::Set,
+            Some(start.unwrap_or(gst::ClockTime::ZERO)),
+            match end {
+                Some(_) => gst::SeekType::Set,
+                None => gst::SeekType::None,
+            },
+            end,
+        )?;
+    }
+
+    pipeline.set_state(gst::State::Playing)?;
+
+    let result = loop {
+        let msg = match bus.timed_pop(gst::ClockTime::from_mseconds(500)) {
+            Some(msg) => msg,
+            None => {
+                print_progress(&pipeline, start, end);
+                continue;
+            }
+        };
+
+        match msg.view() {
+            gst::MessageView::Eos(..) => break Ok(()),
+            gst::MessageView::Error(err) => {
+                break Err(format_err!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                ))
+            }
+            _ => (),
+        }
+    };
+
+    pipeline.set_state(gst::State::Null)?;
+    if result.is_ok() {
+        println!("progress: 100.0% done, wrote {}", cfg.output);
+    }
+
+    result
+}
+
+/// Plugs a raw stream from uridecodebin into an encoder chain feeding `mux`.
+fn link_decoded_pad(
+    pipeline: &gst::Pipeline,
+    pad: &gst::Pad,
+    mux: &gst::Element,
+    profile: &EncodeProfile,
+    scale: Option<f64>,
+) -> Result<(), Error> {
+    let caps = pad
+        .current_caps()
+        .ok_or(format_err!("decoded pad has no caps"))?;
+    let s = caps
+        .structure(0)
+        .ok_or(format_err!("decoded pad has empty caps"))?;
+
+    let chain = if s.name().starts_with("video/") {
+        let mut caps = gst::Caps::builder("video/x-raw")
+            .field("framerate", gst::Fraction::new(profile.framerate as i32, 1));
+        if let (Some(scale), Ok(width), Ok(height)) =
+            (scale, s.get::<i32>("width"), s.get::<i32>("height"))
+        {
+            // Encoders want even dimensions
+            let even = |v: i32| ((v as f64 * scale) as i32 / 2 * 2).max(2);
+            caps = caps
+                .field("width", even(width))
+                .field("height", even(height));
+        }
+        let caps_filter = gst::ElementFactory::make("capsfilter", None)?;
+        caps_filter.set_property("caps", &caps.build())?;
+
+        let mut chain = vec![
+            gst::ElementFactory::make("queue", None)?,
+            gst::ElementFactory::make("videoconvert", None)?,
+            gst::ElementFactory::make("videoscale", None)?,
+            gst::ElementFactory::make("videorate", None)?,
+            caps_filter,
+            profile.make_video_encoder()?,
+        ];
+        chain.extend(profile.make_video_parser()?);
+        chain
+    } else if s.name().starts_with("audio/") {
+        let (audio_enc, audio_parse) = profile.make_audio_encoder()?;
+        let mut chain = vec![
+            gst::ElementFactory::make("queue", None)?,
+            gst::ElementFactory::make("audioconvert", None)?,
+            gst::ElementFactory::make("audioresample", None)?,
+            audio_enc,
+        ];
+        chain.extend(audio_parse);
+        chain
+    } else {
+        info!("ignoring decoded stream {}", s.name());
+        return Ok(());
+    };
+
+    let chain: Vec<&gst::Element> = chain.iter().collect();
+    pipeline.add_many(&chain)?;
+    gst::Element::link_many(&chain)?;
+    chain[chain.len() - 1].link_pads(None, mux, None)?;
+    for e in &chain {
+        e.sync_state_with_parent()?;
+    }
+
+    let sink = chain[0]
+        .static_pad("sink")
+        .ok_or(format_err!("queue has no sink pad"))?;
+    pad.link(&sink)?;
+
+    Ok(())
+}
+
+fn print_progress(
+    pipeline: &gst::Pipeline,
+    start: Option<gst::ClockTime>,
+    end: Option<gst::ClockTime>,
+) {
+    let position = match pipeline.query_position::<gst::ClockTime>() {
+        Some(position) => position,
+        None => return,
+    };
+    let start = start.unwrap_or(gst::ClockTime::ZERO);
+    let end = match end.or_else(|| pipeline.query_duration::<gst::ClockTime>()) {
+        Some(end) if end > start => end,
+        _ => {
+            println!("progress: {}", position);
+            return;
+        }
+    };
+
+    let done = position.nseconds().saturating_sub(start.nseconds());
+    let total = end.nseconds() - start.nseconds();
+    println!(
+        "progress: {:.1}% ({} / {})",
+        (done as f64 / total as f64 * 100.0).min(100.0),
+        gst::ClockTime::from_nseconds(done),
+        gst::ClockTime::from_nseconds(total)
+    );
+}
+
+fn seconds(secs: f64) -> gst::ClockTime {
+    gst::ClockTime::from_nseconds((secs * 1_000_000_000.0) as u64)
+}
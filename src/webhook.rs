@@ -0,0 +1,400 @@
+use failure::{format_err, Error};
+use hmac::{Hmac, Mac, NewMac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::engine::EngineEvent;
+
+const SIGNATURE_HEADER: &str = "X-Tapedeck-Signature";
+const ATTEMPTS: u32 = 5;
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait before the first retry, doubled after every attempt
+const BACKOFF: Duration = Duration::from_secs(1);
+
+/// JSON body POSTed to an engine's webhook.
+#[derive(Debug, Serialize)]
+pub struct WebhookPayload {
+    /// `recording.started`, `recording.failed` or `recording.finished`
+    pub event: &'static str,
+    pub engine_id: u32,
+    /// Unix time the event happened at, in seconds
+    pub timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    /// Whether the recording drained in time, an unfinalized file may be unplayable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finalized: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl WebhookPayload {
+    fn new(event: &'static str, engine_id: u32) -> Self {
+        WebhookPayload {
+            event,
+            engine_id,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |t| t.as_secs()),
+            output: None,
+            duration_secs: None,
+            bytes: None,
+            finalized: None,
+            error: None,
+        }
+    }
+
+    pub fn failed(engine_id: u32, error: String) -> Self {
+        WebhookPayload {
+            error: Some(error),
+            ..WebhookPayload::new("recording.failed", engine_id)
+        }
+    }
+
+    /// Reports a finished recording as failed, keeping what it got to record.
+    pub fn into_failed(self, error: String) -> Self {
+        WebhookPayload {
+            event: "recording.failed",
+            error: Some(error),
+            ..self
+        }
+    }
+
+    /// The payload an engine event should be reported with, if any. Failures are
+    /// reported by the Manager once the engine has ended, so there's only ever
+    /// one of `recording.failed` and `recording.finished` per recording.
+    pub fn from_event(engine_id: u32, event: &EngineEvent) -> Option<Self> {
+        match event {
+            EngineEvent::Recording { output } => Some(WebhookPayload {
+                output: output.clone(),
+                ..WebhookPayload::new("recording.started", engine_id)
+            }),
+            EngineEvent::Finished {
+                output,
+                duration_secs,
+                bytes,
+                finalized,
+            } => Some(WebhookPayload {
+                output: output.clone(),
+                duration_secs: Some(*duration_secs),
+                bytes: Some(*bytes),
+                finalized: Some(*finalized),
+                ..WebhookPayload::new("recording.finished", engine_id)
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Delivers payloads to a webhook from a thread of its own, in the order they
+/// were sent. The thread finishes what's queued once the `Webhook` is dropped.
+pub struct Webhook {
+    tx: mpsc::Sender<WebhookPayload>,
+    worker: thread::JoinHandle<()>,
+}
+
+impl Webhook {
+    pub fn new(url: String, secret: Option<String>) -> Self {
+        let (tx, rx) = mpsc::channel::<WebhookPayload>();
+        let worker = thread::spawn(move || {
+            for payload in rx {
+                if let Err(e) = deliver(&url, secret.as_deref(), &payload, BACKOFF) {
+                    error!("[Webhook] dropping {} for {}: {}", payload.event, url, e);
+                }
+            }
+        });
+
+        Webhook { tx, worker }
+    }
+
+    pub fn send(&self, payload: WebhookPayload) {
+        let _ = self.tx.send(payload);
+    }
+
+    /// Stops taking payloads. The returned thread ends once the queued ones have
+    /// been delivered or given up on.
+    pub fn close(self) -> thread::JoinHandle<()> {
+        let Webhook { tx, worker } = self;
+        drop(tx);
+        worker
+    }
+}
+
+/// POSTs the payload, retrying with exponential backoff on network errors and
+/// on responses that might succeed later.
+fn deliver(
+    url: &str,
+    secret: Option<&str>,
+    payload: &WebhookPayload,
+    mut backoff: Duration,
+) -> Result<(), Error> {
+    let body = serde_json::to_vec(payload)?;
+    let signature = secret.map(|secret| sign(secret, &body)).transpose()?;
+    let client = reqwest::blocking::Client::builder()
+        .timeout(TIMEOUT)
+        .build()?;
+
+    for attempt in 1..=ATTEMPTS {
+        let mut request = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            request = request.header(SIGNATURE_HEADER, format!("sha256={}", signature));
+        }
+
+        match request.send() {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => {
+                let status = response.status();
+                let retryable = status.is_server_error()
+                    || status == reqwest::StatusCode::REQUEST_TIMEOUT
+                    || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+                if !retryable {
+                    return Err(format_err!("rejected with {}", status));
+                }
+                warn!("[Webhook] attempt {} to {} got {}", attempt, url, status);
+            }
+            Err(e) => warn!("[Webhook] attempt {} to {} failed: {}", attempt, url, e),
+        }
+
+        if attempt < ATTEMPTS {
+            thread::sleep(backoff);
+            backoff *= 2;
+        }
+    }
+
+    Err(format_err!("gave up after {} attempts", ATTEMPTS))
+}
+
+/// Hex encoded HMAC-SHA256 of the body, for receivers to check the payload came
+/// from us.
+fn sign(secret: &str, body: &[u8]) -> Result<String, Error> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|e| format_err!("invalid webhook secret: {}", e))?;
+    mac.update(body);
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    pub(crate) struct Request {
+        headers: Vec<(String, String)>,
+        pub(crate) body: Vec<u8>,
+    }
+
+    impl Request {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    /// A local HTTP server answering one request with each of `statuses` in turn.
+    pub(crate) fn stand_in(statuses: Vec<u16>) -> (String, mpsc::Receiver<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+
+                let mut headers = vec![];
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(':') {
+                        Some((key, value)) => {
+                            headers.push((key.trim().to_lowercase(), value.trim().to_owned()))
+                        }
+                        None => break,
+                    }
+                }
+                let len = headers
+                    .iter()
+                    .find(|(key, _)| key == "content-length")
+                    .map_or(0, |(_, value)| value.parse().unwrap());
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+
+                // Recorded before answering so it's there once the client returns
+                let _ = tx.send(Request { headers, body });
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Stand-in\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+
+        (url, rx)
+    }
+
+    fn recording(output: &str) -> WebhookPayload {
+        let event = EngineEvent::Recording {
+            output: Some(output.to_owned()),
+        };
+        WebhookPayload::from_event(7, &event).unwrap()
+    }
+
+    fn deliver_to(url: &str, secret: Option<&str>) -> Result<(), Error> {
+        deliver(
+            url,
+            secret,
+            &recording("/tmp/recording-7.mp4"),
+            Duration::from_millis(10),
+        )
+    }
+
+    #[test]
+    fn signature_matches_body() {
+        let (url, requests) = stand_in(vec![200]);
+
+        deliver_to(&url, Some("s3cret")).unwrap();
+
+        let request = requests.recv().unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+        mac.update(&request.body);
+        let expected = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+        assert_eq!(
+            request.header("x-tapedeck-signature"),
+            Some(expected.as_str())
+        );
+        assert_eq!(request.header("content-type"), Some("application/json"));
+
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body["event"], "recording.started");
+    }
+
+    #[test]
+    fn unsigned_without_secret() {
+        let (url, requests) = stand_in(vec![200]);
+
+        deliver_to(&url, None).unwrap();
+
+        assert_eq!(
+            requests.recv().unwrap().header("x-tapedeck-signature"),
+            None
+        );
+    }
+
+    #[test]
+    fn retries_server_errors_and_rate_limits() {
+        let (url, requests) = stand_in(vec![500, 429, 503, 200]);
+
+        deliver_to(&url, None).unwrap();
+
+        assert_eq!(requests.try_iter().count(), 4);
+    }
+
+    #[test]
+    fn does_not_retry_client_errors() {
+        let (url, requests) = stand_in(vec![400, 200]);
+
+        assert!(deliver_to(&url, None).is_err());
+
+        assert_eq!(requests.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn gives_up_after_every_attempt_failed() {
+        let (url, requests) = stand_in(vec![502; ATTEMPTS as usize]);
+
+        assert!(deliver_to(&url, None).is_err());
+
+        assert_eq!(requests.try_iter().count(), ATTEMPTS as usize);
+    }
+
+    #[test]
+    fn started_payload() {
+        let body = serde_json::to_value(recording("/tmp/recording-7.mp4")).unwrap();
+
+        assert_eq!(body["event"], "recording.started");
+        assert_eq!(body["engine_id"], 7);
+        assert_eq!(body["output"], "/tmp/recording-7.mp4");
+        assert!(body["timestamp"].as_u64().unwrap() > 0);
+        for absent in &["duration_secs", "bytes", "finalized", "error"] {
+            assert!(body.get(absent).is_none(), "{} should be left out", absent);
+        }
+    }
+
+    #[test]
+    fn finished_payload() {
+        let event = EngineEvent::Finished {
+            output: Some("/tmp/recording-7.mp4".to_owned()),
+            duration_secs: 12.5,
+            bytes: 4096,
+            finalized: false,
+        };
+        let payload = WebhookPayload::from_event(7, &event).unwrap();
+        let body = serde_json::to_value(payload).unwrap();
+
+        assert_eq!(body["event"], "recording.finished");
+        assert_eq!(body["output"], "/tmp/recording-7.mp4");
+        assert_eq!(body["duration_secs"], 12.5);
+        assert_eq!(body["bytes"], 4096);
+        assert_eq!(body["finalized"], false);
+        assert!(body.get("error").is_none());
+    }
+
+    #[test]
+    fn failed_payload_keeps_the_recording() {
+        let event = EngineEvent::Finished {
+            output: Some("/tmp/recording-7.mp4".to_owned()),
+            duration_secs: 3.0,
+            bytes: 512,
+            finalized: false,
+        };
+        let payload = WebhookPayload::from_event(7, &event).unwrap();
+        let body = serde_json::to_value(payload.into_failed("pipeline broke".to_owned())).unwrap();
+
+        assert_eq!(body["event"], "recording.failed");
+        assert_eq!(body["error"], "pipeline broke");
+        assert_eq!(body["output"], "/tmp/recording-7.mp4");
+        assert_eq!(body["bytes"], 512);
+    }
+
+    #[test]
+    fn errors_are_left_to_the_manager() {
+        for fatal in &[true, false] {
+            let event = EngineEvent::Error {
+                source: None,
+                message: "pipeline broke".to_owned(),
+                fatal: *fatal,
+            };
+            assert!(WebhookPayload::from_event(7, &event).is_none());
+        }
+    }
+
+    #[test]
+    fn other_events_are_not_reported() {
+        for event in &[
+            EngineEvent::Starting,
+            EngineEvent::Paused,
+            EngineEvent::Resumed,
+            EngineEvent::Eos,
+            EngineEvent::Stopped,
+        ] {
+            assert!(WebhookPayload::from_event(7, event).is_none());
+        }
+    }
+}